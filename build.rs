@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: MIT
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Converts days since 1970-01-01 to (year, month, day)
+fn civil_from_days(days: i64) -> (i64, usize, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as usize, day)
+}
+
+/**
+ * Exports the build date and time in the format of the C __DATE__ and __TIME__ macros, used by
+ * the CMP ident reply. SOURCE_DATE_EPOCH overrides the clock for reproducible builds
+ */
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0)
+        });
+
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let tod = secs.rem_euclid(86400);
+
+    println!(
+        "cargo:rustc-env=CSP_BUILD_DATE={} {:>2} {}",
+        MONTHS[month - 1],
+        day,
+        year
+    );
+    println!(
+        "cargo:rustc-env=CSP_BUILD_TIME={:02}:{:02}:{:02}",
+        tod / 3600,
+        tod % 3600 / 60,
+        tod % 60
+    );
+}
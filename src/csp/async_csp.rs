@@ -0,0 +1,637 @@
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::csp::conn::*;
+use crate::csp::csp::*;
+use crate::csp::interface::{CspIface, CspIfaceStats};
+use crate::csp::sfp::CSP_SFP_HEADER_LEN;
+use crate::csp::types::*;
+
+/// How often the router checks whether the async stack has been dropped
+const CSP_ASYNC_ROUTER_POLL: Duration = Duration::from_millis(100);
+
+/**
+ * Async (tokio) front end of a CSP stack. A router takes every packet received by the stack and
+ * delivers it to the async connections and sockets, packets to the standard service ports with no
+ * socket bound are answered by the service handler. The router is stopped when the last clone is
+ * dropped
+ */
+#[derive(Clone)]
+pub struct CspAsync {
+    csp: CSP,
+    ports: Arc<CspAsyncPorts>,
+    _router: Arc<CspAsyncRouter>,
+}
+
+#[derive(Default)]
+struct CspAsyncPorts {
+    listeners: Mutex<HashMap<u8, CspAsyncBinding<CspAsyncConn>>>,
+    conn_less: Mutex<HashMap<u8, CspAsyncBinding<CspPacket>>>,
+}
+
+/// Queue of a connection or socket with the socket options its packets must meet
+struct CspAsyncBinding<T> {
+    tx: mpsc::Sender<T>,
+    opts: u32,
+    drops: Arc<AtomicU32>,
+}
+
+struct CspAsyncRouter {
+    running: Arc<AtomicBool>,
+}
+
+/**
+ * Async connection, created by CspAsync::connect or CspAsyncListener::accept. It counts against
+ * the connections of the stack and is closed when idle like the sync ones
+ */
+pub struct CspAsyncConn {
+    csp: CSP,
+    slot: CspConnSlot,
+    idout: CspId,
+    opts: u32,
+    rx: mpsc::Receiver<CspPacket>,
+}
+
+/// Socket bound to a port accepting incoming connections
+pub struct CspAsyncListener {
+    ports: Arc<CspAsyncPorts>,
+    port: u8,
+    rx: mpsc::Receiver<CspAsyncConn>,
+    drops: Arc<AtomicU32>,
+}
+
+/// Connectionless socket bound to a port
+pub struct CspAsyncSocket {
+    csp: CSP,
+    ports: Arc<CspAsyncPorts>,
+    port: u8,
+    rx: mpsc::Receiver<CspPacket>,
+    drops: Arc<AtomicU32>,
+}
+
+async fn csp_async_recv<T>(rx: &mut mpsc::Receiver<T>, timeout: Duration) -> Result<T, CspError> {
+    match tokio::time::timeout(timeout, rx.recv()).await {
+        Ok(Some(t)) => Ok(t),
+        Ok(None) => Err(CspError::CspErrReset),
+        Err(_) => Err(CspError::CspErrTimedOut),
+    }
+}
+
+/// Sends packet over conn once the interface it is routed to has room for it, instead of
+/// failing with CspErrNoBufs
+async fn csp_async_send(
+    csp: &CSP,
+    conn: &mut CspConnection,
+    packet: &mut CspPacket,
+) -> Result<(), CspError> {
+    loop {
+        let mut retry = false;
+        if let (Some(iface), _) = csp.csp_route_find(conn.idout.dst as u16) {
+            let mtu = iface.iface().mtu as usize;
+            let mut frames = 1;
+            if csp.csp_get_config().fragmentation && packet.data.len() > mtu {
+                let payload = mtu.saturating_sub(CSP_SFP_HEADER_LEN).max(1);
+                frames = packet.data.len().div_ceil(payload);
+            }
+            if let Some(ready) = iface.tx_ready(frames) {
+                ready.await?;
+                // Fragments already sent cannot be sent again
+                retry = frames == 1;
+            }
+        }
+
+        match csp.csp_send(conn, packet) {
+            // Another sender took the room first
+            Err(CspError::CspErrNoBufs) if retry => {}
+            res => return res,
+        }
+    }
+}
+
+fn csp_async_check_port(port: u8) -> Result<(), CspError> {
+    if port > CSP_MAX_BIND_PORT {
+        warn!("Cannot bind port {}", port);
+        return Err(CspError::CspErrInval(format!("port {} out of range", port)));
+    }
+    Ok(())
+}
+
+impl<T> CspAsyncBinding<T> {
+    fn new(tx: mpsc::Sender<T>, opts: u32, drops: Arc<AtomicU32>) -> Self {
+        Self { tx, opts, drops }
+    }
+
+    /// Checks the socket options, counting the packets dropped for not meeting them
+    fn accepts(&self, id: &CspId, iface: &CspIface) -> bool {
+        csp_opts_accept(self.opts, &self.drops, id, iface)
+    }
+}
+
+impl<T> Clone for CspAsyncBinding<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.tx.clone(), self.opts, self.drops.clone())
+    }
+}
+
+impl CspAsync {
+    /// Starts the router of csp, must be called from within a tokio runtime. The sync csp_read
+    /// must not be used on the stack afterwards, it would take packets from the router
+    pub fn new(csp: CSP) -> Self {
+        let ports = Arc::new(CspAsyncPorts::default());
+        let running = Arc::new(AtomicBool::new(true));
+
+        let router_csp = csp.clone();
+        let router_ports = ports.clone();
+        let router_running = running.clone();
+        tokio::task::spawn_blocking(move || {
+            while router_running.load(Ordering::Relaxed) {
+                match router_csp.csp_read_fifo(CSP_ASYNC_ROUTER_POLL) {
+                    Ok(fifo) => {
+                        if let Some(fifo) = router_csp.csp_route_incoming(fifo) {
+                            router_ports.route(&router_csp, fifo);
+                        }
+                    }
+                    Err(CspError::CspErrTimedOut) => {}
+                    Err(e) => {
+                        error!("CSP async router stopped: {}", e);
+                        break;
+                    }
+                }
+            }
+            debug!("CSP async router done");
+        });
+
+        Self {
+            csp,
+            ports,
+            _router: Arc::new(CspAsyncRouter { running }),
+        }
+    }
+
+    pub fn csp(&self) -> &CSP {
+        &self.csp
+    }
+
+    /// Opens a connection to port dport of node dest. The connection is closed after timeout ms
+    /// without traffic, 0 uses the configured idle timeout. Nothing is exchanged with dest, so
+    /// there is nothing to wait for
+    pub fn connect(
+        &self,
+        prio: CspPriorities,
+        dest: u16,
+        dport: u8,
+        timeout: u32,
+        opts: u8,
+    ) -> Result<CspAsyncConn, CspError> {
+        let mut conn = csp_connect(prio, dest, dport, timeout, opts)?;
+        let (tx, rx) = mpsc::channel(self.csp.csp_get_config().conn_queue_length);
+        let slot =
+            self.csp
+                .csp_conn_open(&mut conn.idout, &CspConnTx::Async(tx), timeout, conn.opts)?;
+
+        Ok(CspAsyncConn {
+            csp: self.csp.clone(),
+            slot,
+            idout: conn.idout,
+            opts: conn.opts,
+            rx,
+        })
+    }
+
+    /// Binds port to accept incoming connections, opts are CSP_SO_* socket options
+    pub fn bind(&self, port: u8, opts: u32) -> Result<CspAsyncListener, CspError> {
+        csp_async_check_port(port)?;
+        csp_socket_opts_validate(opts)?;
+        let mut listeners = csp_lock(&self.ports.listeners);
+        if listeners.contains_key(&port) || csp_lock(&self.ports.conn_less).contains_key(&port) {
+            warn!("Port {} already bound", port);
+            return Err(CspError::CspErrUsed);
+        }
+
+        let (tx, rx) = mpsc::channel(self.csp.csp_get_config().conn_queue_length);
+        let drops = Arc::new(AtomicU32::new(0));
+        listeners.insert(port, CspAsyncBinding::new(tx, opts, drops.clone()));
+        Ok(CspAsyncListener {
+            ports: self.ports.clone(),
+            port,
+            rx,
+            drops,
+        })
+    }
+
+    /// Binds port for connectionless sendto and recvfrom, opts are CSP_SO_* socket options
+    pub fn bind_conn_less(&self, port: u8, opts: u32) -> Result<CspAsyncSocket, CspError> {
+        csp_async_check_port(port)?;
+        csp_socket_opts_validate(opts)?;
+        let listeners = csp_lock(&self.ports.listeners);
+        let mut conn_less = csp_lock(&self.ports.conn_less);
+        if conn_less.contains_key(&port) || listeners.contains_key(&port) {
+            warn!("Port {} already bound", port);
+            return Err(CspError::CspErrUsed);
+        }
+
+        let (tx, rx) = mpsc::channel(self.csp.csp_get_config().conn_queue_length);
+        let drops = Arc::new(AtomicU32::new(0));
+        conn_less.insert(port, CspAsyncBinding::new(tx, opts, drops.clone()));
+        Ok(CspAsyncSocket {
+            csp: self.csp.clone(),
+            ports: self.ports.clone(),
+            port,
+            rx,
+            drops,
+        })
+    }
+}
+
+impl Drop for CspAsyncRouter {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+impl CspAsyncPorts {
+    /// Delivers a received packet claimed by no connection to its listener or socket
+    fn route(&self, csp: &CSP, fifo: CspFIFO) {
+        let packet = fifo.packet;
+        let id = packet.id;
+
+        let listener = csp_lock(&self.listeners).get(&id.dport).cloned();
+        if let Some(listener) = listener {
+            if !listener.accepts(&id, &fifo.iface) {
+                return;
+            }
+
+            let (tx, rx) = mpsc::channel(csp.csp_get_config().conn_queue_length);
+            let tx = CspConnTx::Async(tx);
+            // Connections accepted by the listener inherit its options
+            let key = (id.dport, id.src, id.sport);
+            let slot =
+                match csp.csp_conn_register(key, &tx, 0, listener.opts, listener.drops.clone()) {
+                    Ok(slot) => slot,
+                    Err(e) => {
+                        warn!("Not accepting connection {:?}: {}", key, e);
+                        CspIfaceStats::inc(&fifo.iface.stats.drop);
+                        return;
+                    }
+                };
+            let conn = CspAsyncConn {
+                csp: csp.clone(),
+                slot,
+                // Replies carry a CRC32 when the request did
+                idout: CspId::new()
+                    .pri(id.pri)
+                    .flags(id.flags & CSP_FCRC32)
+                    .dst(id.src)
+                    .dport(id.sport)
+                    .sport(id.dport),
+                opts: listener.opts,
+                rx,
+            };
+            if tx.try_send(packet).is_err() {
+                debug!("Connection queue full, dropping packet");
+                CspIfaceStats::inc(&fifo.iface.stats.drop);
+            }
+            if listener.tx.try_send(conn).is_err() {
+                warn!("Accept queue of port {} full", id.dport);
+                CspIfaceStats::inc(&fifo.iface.stats.drop);
+            }
+            return;
+        }
+
+        let socket = csp_lock(&self.conn_less).get(&id.dport).cloned();
+        if let Some(socket) = socket {
+            if socket.accepts(&id, &fifo.iface) {
+                self.deliver(&socket.tx, packet, &fifo.iface);
+            }
+            return;
+        }
+
+        if id.dport <= CspServices::CspUptime as u8 {
+            csp.csp_service_handler(packet);
+        } else {
+            debug!("No socket bound to port {}, dropping {:?}", id.dport, id);
+            CspIfaceStats::inc(&fifo.iface.stats.drop);
+        }
+    }
+
+    fn deliver(&self, tx: &mpsc::Sender<CspPacket>, packet: CspPacket, iface: &CspIface) {
+        if tx.try_send(packet).is_err() {
+            debug!("Socket queue full, dropping packet");
+            CspIfaceStats::inc(&iface.stats.drop);
+        }
+    }
+}
+
+impl CspAsyncConn {
+    /// Sends packet on the connection, waiting for room on the interface. Fails with
+    /// CspErrConnClosed once the connection was closed for being idle
+    pub async fn send(&self, mut packet: CspPacket) -> Result<(), CspError> {
+        self.csp.csp_conn_touch(&self.slot)?;
+        let mut conn = CspConnection::new();
+        conn.state = ConnState::ConnOpen;
+        conn.opts = self.opts;
+        conn.idout = self.idout;
+        csp_async_send(&self.csp, &mut conn, &mut packet).await
+    }
+
+    /// Waits up to timeout for the next packet of the connection. Fails with CspErrTimedOut
+    /// once the connection was closed for being idle
+    pub async fn read(&mut self, timeout: Duration) -> Result<CspPacket, CspError> {
+        match csp_async_recv(&mut self.rx, timeout).await {
+            Err(CspError::CspErrReset) if self.slot.is_expired() => Err(CspError::CspErrTimedOut),
+            res => res,
+        }
+    }
+
+    /// Outgoing identifier, dst and dport are the peer address and port
+    pub fn idout(&self) -> CspId {
+        self.idout
+    }
+}
+
+impl CspAsyncListener {
+    /// Waits up to timeout for a new incoming connection
+    pub async fn accept(&mut self, timeout: Duration) -> Result<CspAsyncConn, CspError> {
+        csp_async_recv(&mut self.rx, timeout).await
+    }
+
+    /// Number of packets dropped for not meeting the socket options, on the listener and the
+    /// connections it accepted
+    pub fn drops(&self) -> u32 {
+        self.drops.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CspAsyncListener {
+    fn drop(&mut self) {
+        csp_lock(&self.ports.listeners).remove(&self.port);
+    }
+}
+
+impl CspAsyncSocket {
+    /// Sends packet to port dport of node dest from the bound port, waiting for room on the
+    /// interface
+    pub async fn sendto(
+        &self,
+        prio: CspPriorities,
+        dest: u16,
+        dport: u8,
+        mut packet: CspPacket,
+    ) -> Result<(), CspError> {
+        let mut conn = CspConnection::new();
+        conn.state = ConnState::ConnOpen;
+        conn.idout = CspId::new()
+            .pri(prio as u8)
+            .dst(dest as u8)
+            .dport(dport)
+            .sport(self.port);
+        csp_async_send(&self.csp, &mut conn, &mut packet).await
+    }
+
+    /// Waits up to timeout for a packet, its id holds the sender address and port
+    pub async fn recvfrom(&mut self, timeout: Duration) -> Result<CspPacket, CspError> {
+        csp_async_recv(&mut self.rx, timeout).await
+    }
+
+    /// Number of packets dropped for not meeting the socket options
+    pub fn drops(&self) -> u32 {
+        self.drops.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CspAsyncSocket {
+    fn drop(&mut self) {
+        csp_lock(&self.ports.conn_less).remove(&self.port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::config::CspConfig;
+    use crate::csp::interfaces::if_kiss_async::KissAsyncIntf;
+
+    fn kiss_stack(address: u16, io: tokio::io::DuplexStream) -> CspAsync {
+        kiss_stack_with(CspConfig::new().address(address), io)
+    }
+
+    fn kiss_stack_with(config: CspConfig, io: tokio::io::DuplexStream) -> CspAsync {
+        let address = config.address;
+        let csp = CSP::with_config(config).unwrap();
+        let mut intf = CspIface::new(address, 5, "KISS".to_string());
+        intf.rx_channel = Some(csp.get_rx_channel());
+        csp.add_interface(Box::new(KissAsyncIntf::spawn(intf, io)))
+            .unwrap();
+        CspAsync::new(csp)
+    }
+
+    #[tokio::test]
+    async fn async_conn_test() {
+        let (a, b) = tokio::io::duplex(1024);
+        let client = kiss_stack(1, a);
+        let server = kiss_stack(2, b);
+        let timeout = Duration::from_secs(2);
+
+        let mut listener = server.bind(10, CSP_SO_NONE).unwrap();
+        assert!(matches!(
+            server.bind(10, CSP_SO_NONE),
+            Err(CspError::CspErrUsed)
+        ));
+        assert!(matches!(
+            server.bind(40, CSP_SO_NONE),
+            Err(CspError::CspErrInval(_))
+        ));
+
+        let mut conn = client
+            .connect(CspPriorities::CspPrioNormal, 2, 10, 1000, 0)
+            .unwrap();
+        conn.send(CspPacket::new().data(vec![1, 2, 3]))
+            .await
+            .unwrap();
+
+        let mut incoming = listener.accept(timeout).await.unwrap();
+        assert_eq!(incoming.idout().dst, 1);
+        let request = incoming.read(timeout).await.unwrap();
+        assert_eq!(request.data, vec![1, 2, 3]);
+
+        incoming.send(CspPacket::new().data(vec![4])).await.unwrap();
+        let reply = conn.read(timeout).await.unwrap();
+        assert_eq!(reply.id.src, 2);
+        assert_eq!(reply.id.sport, 10);
+        assert_eq!(reply.data, vec![4]);
+
+        assert!(matches!(
+            conn.read(Duration::from_millis(10)).await,
+            Err(CspError::CspErrTimedOut)
+        ));
+    }
+
+    #[tokio::test]
+    async fn async_conn_idle_test() {
+        let (a, b) = tokio::io::duplex(1024);
+        let config = CspConfig::new().address(1).conn_max(1);
+        let client = kiss_stack_with(config, a);
+        let server = kiss_stack_with(CspConfig::new().address(2).conn_max(1), b);
+        let prio = || CspPriorities::CspPrioNormal;
+
+        let mut listener = server.bind(10, CSP_SO_NONE).unwrap();
+        let mut conn = client.connect(prio(), 2, 10, 100, 0).unwrap();
+        assert!(matches!(
+            client.connect(prio(), 2, 10, 0, 0),
+            Err(CspError::CspErrNoBufs)
+        ));
+        conn.send(CspPacket::new().data(vec![1])).await.unwrap();
+        let incoming = listener.accept(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(server.csp().csp_conn_count(), 1);
+
+        // The router closes the idle connection
+        let start = std::time::Instant::now();
+        assert!(matches!(
+            conn.read(Duration::from_secs(2)).await,
+            Err(CspError::CspErrTimedOut)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(matches!(
+            conn.send(CspPacket::new()).await,
+            Err(CspError::CspErrConnClosed)
+        ));
+        assert_eq!(client.csp().csp_conn_count(), 0);
+
+        // Accepted connections count against the limit too
+        let conn = client.connect(prio(), 2, 10, 0, 0).unwrap();
+        conn.send(CspPacket::new().data(vec![2])).await.unwrap();
+        assert!(listener.accept(Duration::from_millis(200)).await.is_err());
+        drop(incoming);
+        assert_eq!(server.csp().csp_conn_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn async_send_backpressure_test() {
+        use crate::csp::interfaces::kiss_codec::KissCsp;
+        use bytes::BytesMut;
+        use tokio::io::AsyncReadExt;
+        use tokio_util::codec::Decoder;
+
+        let (a, mut b) = tokio::io::duplex(16);
+        let node = kiss_stack(1, a);
+        let socket = node.bind_conn_less(20, CSP_SO_NONE).unwrap();
+
+        // More packets than the TX queue holds, sending waits for the peer to read
+        let sender = tokio::spawn(async move {
+            for n in 0..40u8 {
+                let packet = CspPacket::new().data(vec![n]);
+                socket
+                    .sendto(CspPriorities::CspPrioNormal, 2, 21, packet)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut codec = KissCsp::new();
+        let mut buf = BytesMut::new();
+        let mut n = 0;
+        let read = async {
+            while n < 40 {
+                b.read_buf(&mut buf).await.unwrap();
+                while let Some(p) = codec.decode(&mut buf).unwrap() {
+                    assert_eq!(p.data, vec![n]);
+                    n += 1;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap();
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_conn_less_test() {
+        let (a, b) = tokio::io::duplex(1024);
+        let node1 = kiss_stack(1, a);
+        let node2 = kiss_stack(2, b);
+        let timeout = Duration::from_secs(2);
+
+        let mut s1 = node1.bind_conn_less(20, CSP_SO_NONE).unwrap();
+        let mut s2 = node2.bind_conn_less(21, CSP_SO_NONE).unwrap();
+
+        s1.sendto(
+            CspPriorities::CspPrioHigh,
+            2,
+            21,
+            CspPacket::new().data(vec![7]),
+        )
+        .await
+        .unwrap();
+        let p = s2.recvfrom(timeout).await.unwrap();
+        assert_eq!((p.id.src, p.id.sport, p.data.clone()), (1, 20, vec![7]));
+
+        s2.sendto(
+            CspPriorities::CspPrioHigh,
+            p.id.src as u16,
+            p.id.sport,
+            CspPacket::new().data(vec![8]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(s1.recvfrom(timeout).await.unwrap().data, vec![8]);
+
+        drop(s2);
+        assert!(node2.bind_conn_less(21, CSP_SO_NONE).is_ok());
+    }
+
+    #[tokio::test]
+    async fn async_socket_opts_test() {
+        let (a, b) = tokio::io::duplex(1024);
+        let client = kiss_stack(1, a);
+        let server = kiss_stack(2, b);
+        let timeout = Duration::from_secs(2);
+
+        assert!(matches!(
+            server.bind(10, CSP_SO_HMACREQ | CSP_SO_HMACPROHIB),
+            Err(CspError::CspErrInval(_))
+        ));
+        let mut listener = server.bind(10, CSP_SO_HMACREQ).unwrap();
+        let mut socket = server.bind_conn_less(11, CSP_SO_CRC32PROHIB).unwrap();
+
+        let conn = client
+            .connect(CspPriorities::CspPrioNormal, 2, 10, 1000, 0)
+            .unwrap();
+        conn.send(CspPacket::new().data(vec![1])).await.unwrap();
+
+        let mut hmac_conn = CspConnection::new();
+        hmac_conn.state = ConnState::ConnOpen;
+        hmac_conn.idout = conn.idout().flags(CSP_FHMAC);
+        client
+            .csp()
+            .csp_send(&mut hmac_conn, &mut CspPacket::new().data(vec![2]))
+            .unwrap();
+
+        let mut incoming = listener.accept(timeout).await.unwrap();
+        assert_eq!(incoming.read(timeout).await.unwrap().data, vec![2]);
+        assert_eq!(listener.drops(), 1);
+
+        // Later packets of the connection are checked too
+        conn.send(CspPacket::new().data(vec![3])).await.unwrap();
+        let mut crc_conn = CspConnection::new();
+        crc_conn.state = ConnState::ConnOpen;
+        crc_conn.idout = CspId::new().dst(2).dport(11).sport(40).flags(CSP_FCRC32);
+        client
+            .csp()
+            .csp_send(&mut crc_conn, &mut CspPacket::new().data(vec![4]))
+            .unwrap();
+        assert!(socket.recvfrom(Duration::from_millis(200)).await.is_err());
+        assert!(incoming.read(Duration::from_millis(10)).await.is_err());
+        assert_eq!(listener.drops(), 2);
+        assert_eq!(socket.drops(), 1);
+
+        let kiss = server.csp().iflist_get_by_name("KISS").unwrap();
+        assert_eq!(kiss.iface().stats.snapshot().autherr, 3);
+    }
+}
@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: MIT
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::csp::csp::*;
+use crate::csp::interface::*;
+use crate::csp::types::*;
+
+/// How often the bridge thread checks if it was stopped
+const CSP_BRIDGE_POLL: Duration = Duration::from_millis(100);
+
+/**
+ * Relays every packet received on one interface out of the other one, without routing and
+ * without changing the packet: no duplicate check, CRC32 verification or delivery to
+ * connections. While a bridge runs it consumes the stack RX queue, packets received on any
+ * other interface are dropped
+ */
+pub struct CspBridge {
+    running: Arc<AtomicBool>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    drops: Arc<AtomicU32>,
+}
+
+impl CSP {
+    /// Starts bridging the registered interfaces if_a and if_b
+    pub fn csp_bridge_start(&self, if_a: &str, if_b: &str) -> Result<CspBridge, CspError> {
+        if if_a == if_b {
+            return Err(CspError::inval("cannot bridge an interface to itself"));
+        }
+        let a = self
+            .iflist_get_by_name(if_a)
+            .ok_or_else(|| CspError::inval(&format!("unknown interface {}", if_a)))?;
+        let b = self
+            .iflist_get_by_name(if_b)
+            .ok_or_else(|| CspError::inval(&format!("unknown interface {}", if_b)))?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let drops = Arc::new(AtomicU32::new(0));
+        let csp = self.clone();
+        let thread_running = running.clone();
+        let thread_drops = drops.clone();
+
+        info!("Bridging {} <-> {}", if_a, if_b);
+        let thread = thread::Builder::new()
+            .name(format!("bridge-{}-{}", if_a, if_b))
+            .spawn(move || {
+                while thread_running.load(Ordering::Relaxed) {
+                    let mut fifo = match csp.csp_read_raw_fifo(CSP_BRIDGE_POLL) {
+                        Ok(fifo) => fifo,
+                        Err(CspError::CspErrTimedOut) => continue,
+                        Err(_) => break,
+                    };
+
+                    let dest = if fifo.iface.name == a.iface().name {
+                        &b
+                    } else if fifo.iface.name == b.iface().name {
+                        &a
+                    } else {
+                        debug!("Bridge dropping packet from {}", fifo.iface.name);
+                        CspIfaceStats::inc(&fifo.iface.stats.drop);
+                        thread_drops.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    };
+
+                    let via = fifo.packet.id.dst as u16;
+                    if let Err(e) = csp_send_iface(dest.as_ref(), via, &mut fifo.packet, false) {
+                        warn!("Bridge TX on {} failed: {}", dest.iface().name, e);
+                    }
+                }
+            })?;
+
+        Ok(CspBridge {
+            running,
+            thread: Mutex::new(Some(thread)),
+            drops,
+        })
+    }
+}
+
+impl CspBridge {
+    /// Stops relaying and joins the bridge thread
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = csp_lock(&self.thread).take() {
+            if thread.join().is_err() {
+                error!("Bridge thread panicked");
+            }
+            info!("Bridge stopped");
+        }
+    }
+
+    /// Number of packets dropped because they came from neither bridged interface
+    pub fn drops(&self) -> u32 {
+        self.drops.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CspBridge {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::config::CspConfig;
+    use crate::csp::dedup::CspDedupMode;
+    use crate::csp::qfifo::csp_qfifo_write;
+    use crate::csp::rtable::CSP_BROADCAST_ADDR;
+    use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+    struct ChanIntf {
+        intf: CspIface,
+        tx: Mutex<SyncSender<CspPacket>>,
+    }
+
+    impl NextHop for ChanIntf {
+        fn next_hop(
+            &self,
+            _via: u16,
+            packet: &mut CspPacket,
+            _from_me: bool,
+        ) -> Result<(), CspError> {
+            csp_lock(&self.tx)
+                .try_send(packet.clone())
+                .map_err(|_| CspError::CspErrTx)
+        }
+
+        fn iface(&self) -> &CspIface {
+            &self.intf
+        }
+    }
+
+    fn add_chan_intf(csp: &CSP, name: &str) -> (CspIface, Receiver<CspPacket>) {
+        let (tx, rx) = sync_channel(4);
+        let mut intf = CspIface::new(1, 5, name.to_string());
+        intf.rx_channel = Some(csp.get_rx_channel());
+        csp.add_interface(Box::new(ChanIntf {
+            intf: intf.clone(),
+            tx: Mutex::new(tx),
+        }))
+        .unwrap();
+        (intf, rx)
+    }
+
+    #[test]
+    fn bridge_test() {
+        let csp = CSP::new();
+        let (radio, radio_rx) = add_chan_intf(&csp, "KISS");
+        let (lan, lan_rx) = add_chan_intf(&csp, "UDP");
+        let (other, _other_rx) = add_chan_intf(&csp, "CAN");
+
+        assert!(csp.csp_bridge_start("KISS", "KISS").is_err());
+        assert!(csp.csp_bridge_start("KISS", "ETH").is_err());
+        let bridge = csp.csp_bridge_start("KISS", "UDP").unwrap();
+
+        let id = CspId::new().pri(2).src(3).dst(20).dport(10).sport(40);
+        let timeout = Duration::from_secs(1);
+        csp_qfifo_write(CspPacket::new().id(id).data(vec![1, 2]), &radio);
+        let p = lan_rx.recv_timeout(timeout).unwrap();
+        assert_eq!(p.id, id);
+        assert_eq!(p.data, vec![1, 2]);
+
+        csp_qfifo_write(CspPacket::new().id(id).data(vec![3]), &lan);
+        assert_eq!(radio_rx.recv_timeout(timeout).unwrap().data, vec![3]);
+
+        csp_qfifo_write(CspPacket::new().id(id), &other);
+        assert!(lan_rx.recv_timeout(Duration::from_millis(200)).is_err());
+        assert!(radio_rx.try_recv().is_err());
+
+        bridge.stop();
+        assert_eq!(bridge.drops(), 1);
+        let lan_stats = lan.stats.snapshot();
+        assert_eq!(lan_stats.tx, 1);
+        assert_eq!(lan_stats.txbytes, 2);
+        assert_eq!(radio.stats.snapshot().tx, 1);
+        assert_eq!(other.stats.snapshot().drop, 1);
+    }
+
+    #[test]
+    fn bridge_raw_test() {
+        let csp =
+            CSP::with_config(CspConfig::new().address(1).dedup(CspDedupMode::CspDedupAll)).unwrap();
+        let (radio, _radio_rx) = add_chan_intf(&csp, "KISS");
+        let (_lan, lan_rx) = add_chan_intf(&csp, "UDP");
+        let bridge = csp.csp_bridge_start("KISS", "UDP").unwrap();
+
+        // Broadcast with a CRC32, twice: neither stripped nor deduplicated
+        let id = CspId::new()
+            .src(3)
+            .dst(CSP_BROADCAST_ADDR as u8)
+            .dport(10)
+            .flags(CSP_FCRC32);
+        let mut packet = CspPacket::new().id(id).data(vec![1, 2, 3]);
+        packet.csp_crc32_append();
+        let timeout = Duration::from_secs(1);
+        for _ in 0..2 {
+            csp_qfifo_write(packet.clone(), &radio);
+            let p = lan_rx.recv_timeout(timeout).unwrap();
+            assert_eq!(p.id, packet.id);
+            assert_eq!(p.data, packet.data);
+        }
+
+        bridge.stop();
+        assert_eq!(radio.stats.snapshot().drop, 0);
+    }
+}
@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: MIT
 
-pub fn csp_buffer_init() {
-    info!("CSP buffer init");
+pub fn csp_buffer_init(buffers: usize, buffer_data_size: usize) {
+    info!("CSP buffer init ({} x {} bytes)", buffers, buffer_data_size);
 
     // 1 need to create a pool of messages
     // 2 create a queue for these buffers
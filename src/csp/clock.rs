@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: MIT
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::csp::types::*;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CspTimestamp {
+    pub tv_sec: u32,
+    pub tv_nsec: u32,
+}
+
+/**
+ * Clock read and set by the CMP clock service
+ */
+pub trait CspClock: Send {
+    fn get_time(&self) -> CspTimestamp;
+    fn set_time(&mut self, time: &CspTimestamp) -> Result<(), CspError>;
+}
+
+/// Host system clock, it can only be read
+pub struct CspSystemClock;
+
+/// Simulated clock, runs from the last time it was set
+pub struct CspSimClock {
+    time: CspTimestamp,
+    set_at: Instant,
+}
+
+impl CspTimestamp {
+    pub fn from_duration(d: Duration) -> Self {
+        Self {
+            tv_sec: d.as_secs() as u32,
+            tv_nsec: d.subsec_nanos(),
+        }
+    }
+
+    pub fn to_duration(self) -> Duration {
+        Duration::new(self.tv_sec as u64, self.tv_nsec)
+    }
+}
+
+impl CspClock for CspSystemClock {
+    fn get_time(&self) -> CspTimestamp {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        CspTimestamp::from_duration(now)
+    }
+
+    fn set_time(&mut self, _time: &CspTimestamp) -> Result<(), CspError> {
+        warn!("Setting the system clock is not supported");
+        Err(CspError::CspErrNotSup)
+    }
+}
+
+impl CspSimClock {
+    pub fn new(time: CspTimestamp) -> Self {
+        Self {
+            time,
+            set_at: Instant::now(),
+        }
+    }
+}
+
+impl CspClock for CspSimClock {
+    fn get_time(&self) -> CspTimestamp {
+        CspTimestamp::from_duration(self.time.to_duration() + self.set_at.elapsed())
+    }
+
+    fn set_time(&mut self, time: &CspTimestamp) -> Result<(), CspError> {
+        self.time = *time;
+        self.set_at = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_test() {
+        let system = CspSystemClock {};
+        assert!(system.get_time().tv_sec > 1_600_000_000);
+
+        let mut sim = CspSimClock::new(CspTimestamp::default());
+        assert!(sim.get_time().tv_sec < 10);
+
+        sim.set_time(&CspTimestamp {
+            tv_sec: 1000,
+            tv_nsec: 500,
+        })
+        .unwrap();
+        let t = sim.get_time();
+        assert!(t.tv_sec == 1000 || t.tv_sec == 1001);
+    }
+}
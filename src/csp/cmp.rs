@@ -0,0 +1,701 @@
+// SPDX-License-Identifier: MIT
+
+use byteorder::ByteOrder;
+use std::time::{Duration, Instant};
+
+use crate::csp::clock::*;
+use crate::csp::csp::*;
+use crate::csp::rtable::*;
+use crate::csp::types::*;
+
+pub const CSP_CMP_REQUEST: u8 = 0x00;
+pub const CSP_CMP_REPLY: u8 = 0xFF;
+
+pub const CSP_CMP_IDENT: u8 = 1;
+pub const CSP_CMP_ROUTE_SET: u8 = 2;
+pub const CSP_CMP_IF_STATS: u8 = 3;
+pub const CSP_CMP_PEEK: u8 = 4;
+pub const CSP_CMP_POKE: u8 = 5;
+pub const CSP_CMP_CLOCK: u8 = 6;
+
+pub const CSP_HOSTNAME_LEN: usize = 20;
+pub const CSP_MODEL_LEN: usize = 30;
+pub const CSP_CMP_IDENT_REV_LEN: usize = 20;
+pub const CSP_CMP_IDENT_DATE_LEN: usize = 12;
+pub const CSP_CMP_IDENT_TIME_LEN: usize = 9;
+pub const CSP_CMP_ROUTE_IFACE_LEN: usize = 11;
+pub const CSP_CMP_PEEK_MAX_LEN: usize = 200;
+pub const CSP_CMP_POKE_MAX_LEN: usize = 200;
+
+/// Build date and time reported by ident, formatted as the C __DATE__ and __TIME__ macros
+pub const CSP_CMP_IDENT_DATE: &str = env!("CSP_BUILD_DATE");
+pub const CSP_CMP_IDENT_TIME: &str = env!("CSP_BUILD_TIME");
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CspCmpIdent {
+    pub hostname: String,
+    pub model: String,
+    pub revision: String,
+    pub date: String,
+    pub time: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CspCmpRouteSet {
+    pub dest_node: u8,
+    pub next_hop_via: u8,
+    pub interface: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CspCmpIfStats {
+    pub interface: String,
+    pub tx: u32,
+    pub rx: u32,
+    pub tx_error: u32,
+    pub rx_error: u32,
+    pub drop: u32,
+    pub autherr: u32,
+    pub frame: u32,
+    pub txbytes: u32,
+    pub rxbytes: u32,
+    pub irq: u32,
+}
+
+/// Body of peek and poke messages, data holds len bytes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CspCmpMem {
+    pub addr: u32,
+    pub len: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CspCmpBody {
+    Ident(CspCmpIdent),
+    RouteSet(CspCmpRouteSet),
+    IfStats(CspCmpIfStats),
+    Peek(CspCmpMem),
+    Poke(CspCmpMem),
+    Clock(CspTimestamp),
+}
+
+/**
+ * CSP Management Protocol message, encoded as the packed C struct csp_cmp_message
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CspCmpMessage {
+    pub msg_type: u8,
+    pub body: CspCmpBody,
+}
+
+fn cmp_put_str(buf: &mut Vec<u8>, s: &str, len: usize) {
+    let mut field = vec![0u8; len];
+    let bytes = s.as_bytes();
+    let n = bytes.len().min(len - 1);
+    field[..n].copy_from_slice(&bytes[..n]);
+    buf.extend_from_slice(&field);
+}
+
+fn cmp_get_str(data: &[u8]) -> String {
+    let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).to_string()
+}
+
+fn cmp_put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn cmp_put_mem(buf: &mut Vec<u8>, mem: &CspCmpMem) {
+    cmp_put_u32(buf, mem.addr);
+    buf.push(mem.len);
+    let mut data = vec![0u8; CSP_CMP_PEEK_MAX_LEN];
+    let n = mem.data.len().min(CSP_CMP_PEEK_MAX_LEN);
+    data[..n].copy_from_slice(&mem.data[..n]);
+    buf.extend_from_slice(&data);
+}
+
+fn cmp_get_mem(data: &[u8]) -> Option<CspCmpMem> {
+    let len = data[4];
+    if len as usize > CSP_CMP_PEEK_MAX_LEN {
+        return None;
+    }
+    Some(CspCmpMem {
+        addr: byteorder::BigEndian::read_u32(&data[0..4]),
+        len,
+        data: data[5..5 + len as usize].to_vec(),
+    })
+}
+
+impl CspCmpMessage {
+    pub fn new(body: CspCmpBody) -> Self {
+        Self {
+            msg_type: CSP_CMP_REQUEST,
+            body,
+        }
+    }
+
+    pub fn reply(mut self) -> Self {
+        self.msg_type = CSP_CMP_REPLY;
+        self
+    }
+
+    pub fn code(&self) -> u8 {
+        match self.body {
+            CspCmpBody::Ident(_) => CSP_CMP_IDENT,
+            CspCmpBody::RouteSet(_) => CSP_CMP_ROUTE_SET,
+            CspCmpBody::IfStats(_) => CSP_CMP_IF_STATS,
+            CspCmpBody::Peek(_) => CSP_CMP_PEEK,
+            CspCmpBody::Poke(_) => CSP_CMP_POKE,
+            CspCmpBody::Clock(_) => CSP_CMP_CLOCK,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.msg_type, self.code()];
+
+        match &self.body {
+            CspCmpBody::Ident(ident) => {
+                cmp_put_str(&mut buf, &ident.hostname, CSP_HOSTNAME_LEN);
+                cmp_put_str(&mut buf, &ident.model, CSP_MODEL_LEN);
+                cmp_put_str(&mut buf, &ident.revision, CSP_CMP_IDENT_REV_LEN);
+                cmp_put_str(&mut buf, &ident.date, CSP_CMP_IDENT_DATE_LEN);
+                cmp_put_str(&mut buf, &ident.time, CSP_CMP_IDENT_TIME_LEN);
+            }
+            CspCmpBody::RouteSet(route) => {
+                buf.push(route.dest_node);
+                buf.push(route.next_hop_via);
+                cmp_put_str(&mut buf, &route.interface, CSP_CMP_ROUTE_IFACE_LEN);
+            }
+            CspCmpBody::IfStats(stats) => {
+                cmp_put_str(&mut buf, &stats.interface, CSP_CMP_ROUTE_IFACE_LEN);
+                for value in [
+                    stats.tx,
+                    stats.rx,
+                    stats.tx_error,
+                    stats.rx_error,
+                    stats.drop,
+                    stats.autherr,
+                    stats.frame,
+                    stats.txbytes,
+                    stats.rxbytes,
+                    stats.irq,
+                ] {
+                    cmp_put_u32(&mut buf, value);
+                }
+            }
+            CspCmpBody::Peek(mem) | CspCmpBody::Poke(mem) => cmp_put_mem(&mut buf, mem),
+            CspCmpBody::Clock(time) => {
+                cmp_put_u32(&mut buf, time.tv_sec);
+                cmp_put_u32(&mut buf, time.tv_nsec);
+            }
+        }
+
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 2 {
+            return None;
+        }
+
+        let msg_type = data[0];
+        let code = data[1];
+        let data = &data[2..];
+
+        if data.len() < cmp_body_len(code)? {
+            return None;
+        }
+
+        let body = match code {
+            CSP_CMP_IDENT => {
+                let mut offset = 0;
+                let mut field = |len: usize| {
+                    let s = cmp_get_str(&data[offset..offset + len]);
+                    offset += len;
+                    s
+                };
+                CspCmpBody::Ident(CspCmpIdent {
+                    hostname: field(CSP_HOSTNAME_LEN),
+                    model: field(CSP_MODEL_LEN),
+                    revision: field(CSP_CMP_IDENT_REV_LEN),
+                    date: field(CSP_CMP_IDENT_DATE_LEN),
+                    time: field(CSP_CMP_IDENT_TIME_LEN),
+                })
+            }
+            CSP_CMP_ROUTE_SET => CspCmpBody::RouteSet(CspCmpRouteSet {
+                dest_node: data[0],
+                next_hop_via: data[1],
+                interface: cmp_get_str(&data[2..2 + CSP_CMP_ROUTE_IFACE_LEN]),
+            }),
+            CSP_CMP_PEEK => CspCmpBody::Peek(cmp_get_mem(data)?),
+            CSP_CMP_POKE => CspCmpBody::Poke(cmp_get_mem(data)?),
+            CSP_CMP_CLOCK => CspCmpBody::Clock(CspTimestamp {
+                tv_sec: byteorder::BigEndian::read_u32(&data[0..4]),
+                tv_nsec: byteorder::BigEndian::read_u32(&data[4..8]),
+            }),
+            CSP_CMP_IF_STATS => {
+                let counters = &data[CSP_CMP_ROUTE_IFACE_LEN..];
+                let counter = |n: usize| byteorder::BigEndian::read_u32(&counters[n * 4..]);
+                CspCmpBody::IfStats(CspCmpIfStats {
+                    interface: cmp_get_str(&data[..CSP_CMP_ROUTE_IFACE_LEN]),
+                    tx: counter(0),
+                    rx: counter(1),
+                    tx_error: counter(2),
+                    rx_error: counter(3),
+                    drop: counter(4),
+                    autherr: counter(5),
+                    frame: counter(6),
+                    txbytes: counter(7),
+                    rxbytes: counter(8),
+                    irq: counter(9),
+                })
+            }
+            _ => return None,
+        };
+
+        Some(Self { msg_type, body })
+    }
+}
+
+fn cmp_body_len(code: u8) -> Option<usize> {
+    match code {
+        CSP_CMP_IDENT => Some(
+            CSP_HOSTNAME_LEN
+                + CSP_MODEL_LEN
+                + CSP_CMP_IDENT_REV_LEN
+                + CSP_CMP_IDENT_DATE_LEN
+                + CSP_CMP_IDENT_TIME_LEN,
+        ),
+        CSP_CMP_ROUTE_SET => Some(2 + CSP_CMP_ROUTE_IFACE_LEN),
+        CSP_CMP_IF_STATS => Some(CSP_CMP_ROUTE_IFACE_LEN + 10 * 4),
+        CSP_CMP_PEEK => Some(5 + CSP_CMP_PEEK_MAX_LEN),
+        CSP_CMP_POKE => Some(5 + CSP_CMP_POKE_MAX_LEN),
+        CSP_CMP_CLOCK => Some(8),
+        _ => None,
+    }
+}
+
+impl CSP {
+    /// Handles a CMP request, returning the encoded reply if there is one
+    pub(crate) fn csp_cmp_handler(&self, packet: &CspPacket) -> Option<Vec<u8>> {
+        let request = match CspCmpMessage::decode(&packet.data) {
+            Some(r) if r.msg_type == CSP_CMP_REQUEST => r,
+            _ => {
+                warn!("Invalid CMP request");
+                return None;
+            }
+        };
+
+        let body = match request.body {
+            CspCmpBody::Ident(_) => CspCmpBody::Ident(CspCmpIdent {
+                hostname: self.csp_get_hostname().to_string(),
+                model: self.csp_get_model().to_string(),
+                revision: self.csp_get_revision().to_string(),
+                date: CSP_CMP_IDENT_DATE.to_string(),
+                time: CSP_CMP_IDENT_TIME.to_string(),
+            }),
+            CspCmpBody::RouteSet(route) => {
+                if self
+                    .csp_rtable_set(
+                        route.dest_node as u16,
+                        CSP_ID_HOST_SIZE,
+                        &route.interface,
+                        route.next_hop_via as u16,
+                    )
+                    .is_err()
+                {
+                    return None;
+                }
+                CspCmpBody::RouteSet(route)
+            }
+            CspCmpBody::IfStats(stats) => {
+                let intf = self.iflist_get_by_name(&stats.interface)?;
+                let counters = intf.iface().stats.snapshot();
+                CspCmpBody::IfStats(CspCmpIfStats {
+                    interface: stats.interface,
+                    tx: counters.tx,
+                    rx: counters.rx,
+                    tx_error: counters.tx_error,
+                    rx_error: counters.rx_error,
+                    drop: counters.drop,
+                    autherr: counters.autherr,
+                    frame: counters.frame,
+                    txbytes: counters.txbytes,
+                    rxbytes: counters.rxbytes,
+                    irq: counters.irq,
+                })
+            }
+            CspCmpBody::Peek(mem) => {
+                let data = self
+                    .csp_memory_map()
+                    .as_ref()?
+                    .peek(mem.addr, mem.len as usize)
+                    .ok()?;
+                CspCmpBody::Peek(CspCmpMem { data, ..mem })
+            }
+            CspCmpBody::Poke(mem) => {
+                self.csp_memory_map()
+                    .as_mut()?
+                    .poke(mem.addr, &mem.data)
+                    .ok()?;
+                CspCmpBody::Poke(mem)
+            }
+            CspCmpBody::Clock(time) => {
+                if time.tv_sec != 0 {
+                    if let Err(e) = self.csp_clock_set_time(&time) {
+                        warn!("CMP clock set failed: {}", e);
+                    }
+                }
+                // The reply always carries the current time, so the client sees if it was set
+                CspCmpBody::Clock(self.csp_clock_get_time())
+            }
+        };
+
+        Some(CspCmpMessage::new(body).reply().encode())
+    }
+
+    /// Sends a CMP request to node and waits for the matching reply
+    pub fn cmp_transaction(
+        &self,
+        node: u16,
+        timeout: u32,
+        request: CspCmpMessage,
+    ) -> Result<CspCmpMessage, CspError> {
+        let mut conn = self.csp_connect(
+            CspPriorities::CspPrioNormal,
+            node,
+            CspServices::CspCMP as u8,
+            timeout,
+            0,
+        )?;
+
+        let code = request.code();
+        let mut packet = CspPacket::new().data(request.encode());
+        self.csp_send(&mut conn, &mut packet)?;
+
+        let deadline = Instant::now() + Duration::from_millis(timeout as u64);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let reply = conn.read(remaining)?;
+
+            match CspCmpMessage::decode(&reply.data) {
+                Some(m) if m.msg_type == CSP_CMP_REPLY && m.code() == code => return Ok(m),
+                _ => warn!("Invalid CMP reply from {}", node),
+            }
+        }
+    }
+
+    pub fn cmp_ident(&self, node: u16, timeout: u32) -> Result<CspCmpIdent, CspError> {
+        let request = CspCmpMessage::new(CspCmpBody::Ident(CspCmpIdent::default()));
+        match self.cmp_transaction(node, timeout, request)?.body {
+            CspCmpBody::Ident(ident) => Ok(ident),
+            _ => Err(CspError::inval("unexpected CMP reply")),
+        }
+    }
+
+    pub fn cmp_route_set(
+        &self,
+        node: u16,
+        timeout: u32,
+        route: CspCmpRouteSet,
+    ) -> Result<CspCmpRouteSet, CspError> {
+        let request = CspCmpMessage::new(CspCmpBody::RouteSet(route));
+        match self.cmp_transaction(node, timeout, request)?.body {
+            CspCmpBody::RouteSet(route) => Ok(route),
+            _ => Err(CspError::inval("unexpected CMP reply")),
+        }
+    }
+
+    pub fn cmp_if_stats(
+        &self,
+        node: u16,
+        timeout: u32,
+        interface: &str,
+    ) -> Result<CspCmpIfStats, CspError> {
+        let request = CspCmpMessage::new(CspCmpBody::IfStats(CspCmpIfStats {
+            interface: interface.to_string(),
+            ..Default::default()
+        }));
+        match self.cmp_transaction(node, timeout, request)?.body {
+            CspCmpBody::IfStats(stats) => Ok(stats),
+            _ => Err(CspError::inval("unexpected CMP reply")),
+        }
+    }
+
+    /// Reads len bytes at addr from the memory map of node
+    pub fn cmp_peek(
+        &self,
+        node: u16,
+        timeout: u32,
+        addr: u32,
+        len: usize,
+    ) -> Result<Vec<u8>, CspError> {
+        if len > CSP_CMP_PEEK_MAX_LEN {
+            return Err(CspError::inval("peek length too long"));
+        }
+
+        let request = CspCmpMessage::new(CspCmpBody::Peek(CspCmpMem {
+            addr,
+            len: len as u8,
+            data: Vec::new(),
+        }));
+        match self.cmp_transaction(node, timeout, request)?.body {
+            CspCmpBody::Peek(mem) if mem.addr == addr && mem.data.len() == len => Ok(mem.data),
+            _ => Err(CspError::inval("unexpected CMP reply")),
+        }
+    }
+
+    /// Writes data at addr in the memory map of node
+    pub fn cmp_poke(
+        &self,
+        node: u16,
+        timeout: u32,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), CspError> {
+        if data.len() > CSP_CMP_POKE_MAX_LEN {
+            return Err(CspError::inval("poke length too long"));
+        }
+
+        let request = CspCmpMessage::new(CspCmpBody::Poke(CspCmpMem {
+            addr,
+            len: data.len() as u8,
+            data: data.to_vec(),
+        }));
+        match self.cmp_transaction(node, timeout, request)?.body {
+            CspCmpBody::Poke(mem) if mem.addr == addr => Ok(()),
+            _ => Err(CspError::inval("unexpected CMP reply")),
+        }
+    }
+
+    /// Reads the clock of node
+    pub fn cmp_clock_get(&self, node: u16, timeout: u32) -> Result<CspTimestamp, CspError> {
+        self.cmp_clock_set(node, timeout, CspTimestamp::default())
+    }
+
+    /// Sets the clock of node, returning the time it reports after setting it. A zero
+    /// tv_sec leaves the remote clock untouched, if node cannot set its clock the returned
+    /// time is its unchanged current time
+    pub fn cmp_clock_set(
+        &self,
+        node: u16,
+        timeout: u32,
+        time: CspTimestamp,
+    ) -> Result<CspTimestamp, CspError> {
+        let request = CspCmpMessage::new(CspCmpBody::Clock(time));
+        match self.cmp_transaction(node, timeout, request)?.body {
+            CspCmpBody::Clock(time) => Ok(time),
+            _ => Err(CspError::inval("unexpected CMP reply")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::interface::*;
+    use crate::csp::memmap::*;
+
+    struct TestIntf {
+        intf: CspIface,
+    }
+
+    impl NextHop for TestIntf {
+        fn next_hop(
+            &self,
+            _via: u16,
+            _packet: &mut CspPacket,
+            _from_me: bool,
+        ) -> Result<(), CspError> {
+            Ok(())
+        }
+
+        fn iface(&self) -> &CspIface {
+            &self.intf
+        }
+    }
+
+    fn request_packet(body: CspCmpBody) -> CspPacket {
+        CspPacket::new()
+            .id(CspId::new().dport(CspServices::CspCMP as u8))
+            .data(CspCmpMessage::new(body).encode())
+    }
+
+    #[test]
+    fn cmp_encode_decode_test() {
+        let msg = CspCmpMessage::new(CspCmpBody::Ident(CspCmpIdent {
+            hostname: "obc".to_string(),
+            model: "a very long model name that does not fit".to_string(),
+            revision: "1.0".to_string(),
+            date: "Aug 14 2022".to_string(),
+            time: "12:00:00".to_string(),
+        }))
+        .reply();
+        let data = msg.encode();
+        assert_eq!(data.len(), 2 + 91);
+        assert_eq!(data[0], CSP_CMP_REPLY);
+        assert_eq!(data[1], CSP_CMP_IDENT);
+
+        let decoded = CspCmpMessage::decode(&data).unwrap();
+        match decoded.body {
+            CspCmpBody::Ident(ident) => {
+                assert_eq!(ident.hostname, "obc");
+                assert_eq!(ident.model, "a very long model name that d");
+                assert_eq!(ident.time, "12:00:00");
+            }
+            _ => panic!("Wrong CMP message"),
+        }
+
+        let msg = CspCmpMessage::new(CspCmpBody::IfStats(CspCmpIfStats {
+            interface: "KISS".to_string(),
+            tx: 1,
+            rxbytes: 0x01020304,
+            irq: 10,
+            ..Default::default()
+        }));
+        let data = msg.encode();
+        assert_eq!(data.len(), 2 + 51);
+        assert_eq!(CspCmpMessage::decode(&data).unwrap(), msg);
+
+        assert!(CspCmpMessage::decode(&data[..20]).is_none());
+        assert!(CspCmpMessage::decode(&[CSP_CMP_REQUEST, 99, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn cmp_handler_test() {
+        let csp = CSP::new();
+        let intf = CspIface::new(5, 5, "KISS".to_string());
+        CspIfaceStats::add(&intf.stats.tx, 7);
+        CspIfaceStats::add(&intf.stats.rx_error, 2);
+        csp.add_interface(Box::new(TestIntf { intf })).unwrap();
+
+        let reply = csp
+            .csp_cmp_handler(&request_packet(CspCmpBody::Ident(CspCmpIdent::default())))
+            .unwrap();
+        match CspCmpMessage::decode(&reply).unwrap().body {
+            CspCmpBody::Ident(ident) => {
+                assert_eq!(ident.revision, env!("CARGO_PKG_VERSION"));
+                assert_eq!(ident.date.len(), CSP_CMP_IDENT_DATE_LEN - 1);
+                assert_eq!(ident.time.len(), CSP_CMP_IDENT_TIME_LEN - 1);
+                assert_eq!(ident.date, CSP_CMP_IDENT_DATE);
+            }
+            _ => panic!("Wrong CMP reply"),
+        }
+
+        let reply = csp
+            .csp_cmp_handler(&request_packet(CspCmpBody::IfStats(CspCmpIfStats {
+                interface: "KISS".to_string(),
+                ..Default::default()
+            })))
+            .unwrap();
+        let reply = CspCmpMessage::decode(&reply).unwrap();
+        assert_eq!(reply.msg_type, CSP_CMP_REPLY);
+        match reply.body {
+            CspCmpBody::IfStats(stats) => {
+                assert_eq!(stats.tx, 7);
+                assert_eq!(stats.rx_error, 2);
+            }
+            _ => panic!("Wrong CMP reply"),
+        }
+
+        let route = CspCmpRouteSet {
+            dest_node: 10,
+            next_hop_via: 12,
+            interface: "UNKNOWN".to_string(),
+        };
+        assert!(csp
+            .csp_cmp_handler(&request_packet(CspCmpBody::RouteSet(route.clone())))
+            .is_none());
+        assert!(csp.csp_rtable().find(10).is_none());
+
+        let route = CspCmpRouteSet {
+            interface: "KISS".to_string(),
+            ..route
+        };
+        assert!(csp
+            .csp_cmp_handler(&request_packet(CspCmpBody::RouteSet(route)))
+            .is_some());
+        let rtable = csp.csp_rtable();
+        let r = rtable.find(10).unwrap();
+        assert_eq!(r.iface, "KISS");
+        assert_eq!(r.via, 12);
+    }
+
+    #[test]
+    fn cmp_peek_poke_handler_test() {
+        let peek = CspCmpBody::Peek(CspCmpMem {
+            addr: 0x1000,
+            len: 4,
+            data: Vec::new(),
+        });
+
+        let csp = CSP::new();
+        assert!(csp.csp_cmp_handler(&request_packet(peek.clone())).is_none());
+
+        let mut mem = CspMemRegions::new();
+        mem.add("sim", 0x1000, vec![1, 2, 3, 4, 5, 6, 7, 8], true)
+            .unwrap();
+        csp.csp_set_memory_map(Box::new(mem));
+
+        let reply = csp.csp_cmp_handler(&request_packet(peek.clone())).unwrap();
+        assert_eq!(reply.len(), 2 + 5 + CSP_CMP_PEEK_MAX_LEN);
+        match CspCmpMessage::decode(&reply).unwrap().body {
+            CspCmpBody::Peek(mem) => assert_eq!(mem.data, vec![1, 2, 3, 4]),
+            _ => panic!("Wrong CMP reply"),
+        }
+
+        let poke = CspCmpBody::Poke(CspCmpMem {
+            addr: 0x1002,
+            len: 2,
+            data: vec![0xAA, 0xBB],
+        });
+        assert!(csp.csp_cmp_handler(&request_packet(poke)).is_some());
+
+        let reply = csp.csp_cmp_handler(&request_packet(peek)).unwrap();
+        match CspCmpMessage::decode(&reply).unwrap().body {
+            CspCmpBody::Peek(mem) => assert_eq!(mem.data, vec![1, 2, 0xAA, 0xBB]),
+            _ => panic!("Wrong CMP reply"),
+        }
+
+        let out_of_bounds = CspCmpBody::Peek(CspCmpMem {
+            addr: 0x1006,
+            len: 4,
+            data: Vec::new(),
+        });
+        assert!(csp
+            .csp_cmp_handler(&request_packet(out_of_bounds))
+            .is_none());
+    }
+
+    #[test]
+    fn cmp_clock_handler_test() {
+        let get = CspCmpBody::Clock(CspTimestamp::default());
+        let set = CspCmpBody::Clock(CspTimestamp {
+            tv_sec: 1_000_000,
+            tv_nsec: 0,
+        });
+
+        let csp = CSP::new();
+        assert!(csp.csp_cmp_handler(&request_packet(get.clone())).is_some());
+        let reply = csp.csp_cmp_handler(&request_packet(set.clone())).unwrap();
+        match CspCmpMessage::decode(&reply).unwrap().body {
+            CspCmpBody::Clock(time) => assert!(time.tv_sec > 1_000_000_000),
+            _ => panic!("Wrong CMP reply"),
+        }
+
+        csp.csp_set_clock(Box::new(CspSimClock::new(CspTimestamp::default())));
+        let reply = csp.csp_cmp_handler(&request_packet(set)).unwrap();
+        assert_eq!(reply.len(), 2 + 8);
+        match CspCmpMessage::decode(&reply).unwrap().body {
+            CspCmpBody::Clock(time) => assert!(time.tv_sec >= 1_000_000),
+            _ => panic!("Wrong CMP reply"),
+        }
+
+        let reply = csp.csp_cmp_handler(&request_packet(get)).unwrap();
+        match CspCmpMessage::decode(&reply).unwrap().body {
+            CspCmpBody::Clock(time) => assert!(time.tv_sec >= 1_000_000),
+            _ => panic!("Wrong CMP reply"),
+        }
+    }
+}
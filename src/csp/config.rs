@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: MIT
+
+use crate::csp::cmp::*;
+use crate::csp::dedup::CspDedupMode;
+use crate::csp::rtable::CSP_ID_HOST_SIZE;
+use crate::csp::types::*;
+
+/// Default RDP connection parameters, RDP itself is not implemented yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct CspRdpConfig {
+    pub window_size: u32,
+    pub conn_timeout_ms: u32,
+    pub packet_timeout_ms: u32,
+    pub delayed_acks: bool,
+    pub ack_timeout_ms: u32,
+    pub ack_delay_count: u32,
+}
+
+/**
+ * Stack configuration, consumed by CSP::with_config and csp_init
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CspConfig {
+    pub address: u16,
+    pub hostname: String,
+    pub model: String,
+    pub revision: String,
+    pub buffers: usize,
+    /// Largest packet payload the stack handles, interface MTUs must not exceed it
+    pub buffer_data_size: usize,
+    pub conn_max: usize,
+    pub conn_queue_length: usize,
+    /// Connections without traffic for this long are closed, 0 keeps them open
+    pub conn_idle_timeout_ms: u32,
+    pub fifo_length: usize,
+    pub rdp: CspRdpConfig,
+    pub dedup: CspDedupMode,
+    pub dedup_count: usize,
+    pub dedup_window_ms: u32,
+    /// Packets larger than the interface MTU are sent with SFP instead of being rejected
+    pub fragmentation: bool,
+}
+
+impl CspRdpConfig {
+    pub fn new() -> Self {
+        Self {
+            window_size: 4,
+            conn_timeout_ms: 10000,
+            packet_timeout_ms: 1000,
+            delayed_acks: true,
+            ack_timeout_ms: 250,
+            ack_delay_count: 2,
+        }
+    }
+}
+
+impl Default for CspRdpConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CspConfig {
+    pub fn new() -> Self {
+        Self {
+            address: 1,
+            hostname: String::new(),
+            model: String::new(),
+            revision: env!("CARGO_PKG_VERSION").to_string(),
+            buffers: 10,
+            buffer_data_size: 256,
+            conn_max: 10,
+            conn_queue_length: 10,
+            conn_idle_timeout_ms: 0,
+            fifo_length: 16,
+            rdp: CspRdpConfig::new(),
+            dedup: CspDedupMode::CspDedupOff,
+            dedup_count: 16,
+            dedup_window_ms: 1000,
+            fragmentation: false,
+        }
+    }
+
+    pub fn address(mut self, address: u16) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = hostname.to_string();
+        self
+    }
+
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    pub fn revision(mut self, revision: &str) -> Self {
+        self.revision = revision.to_string();
+        self
+    }
+
+    pub fn buffers(mut self, buffers: usize, buffer_data_size: usize) -> Self {
+        self.buffers = buffers;
+        self.buffer_data_size = buffer_data_size;
+        self
+    }
+
+    pub fn conn_max(mut self, conn_max: usize) -> Self {
+        self.conn_max = conn_max;
+        self
+    }
+
+    pub fn conn_queue_length(mut self, conn_queue_length: usize) -> Self {
+        self.conn_queue_length = conn_queue_length;
+        self
+    }
+
+    pub fn conn_idle_timeout(mut self, conn_idle_timeout_ms: u32) -> Self {
+        self.conn_idle_timeout_ms = conn_idle_timeout_ms;
+        self
+    }
+
+    pub fn fifo_length(mut self, fifo_length: usize) -> Self {
+        self.fifo_length = fifo_length;
+        self
+    }
+
+    pub fn rdp(mut self, rdp: CspRdpConfig) -> Self {
+        self.rdp = rdp;
+        self
+    }
+
+    pub fn dedup(mut self, dedup: CspDedupMode) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Remembers the last dedup_count packets for dedup_window_ms
+    pub fn dedup_window(mut self, dedup_count: usize, dedup_window_ms: u32) -> Self {
+        self.dedup_count = dedup_count;
+        self.dedup_window_ms = dedup_window_ms;
+        self
+    }
+
+    pub fn fragmentation(mut self, fragmentation: bool) -> Self {
+        self.fragmentation = fragmentation;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), CspError> {
+        let invalid = |what: &str| {
+            warn!("Invalid configuration: {}", what);
+            Err(CspError::inval(what))
+        };
+
+        if self.address >= 1 << CSP_ID_HOST_SIZE {
+            return invalid("address out of range");
+        }
+        if self.hostname.len() >= CSP_HOSTNAME_LEN
+            || self.model.len() >= CSP_MODEL_LEN
+            || self.revision.len() >= CSP_CMP_IDENT_REV_LEN
+        {
+            return invalid("identity string too long");
+        }
+        if self.buffers == 0
+            || self.buffer_data_size == 0
+            || self.conn_max == 0
+            || self.conn_queue_length == 0
+            || self.fifo_length == 0
+        {
+            return invalid("sizes must not be zero");
+        }
+        if self.rdp.window_size == 0 {
+            return invalid("RDP window size must not be zero");
+        }
+        if self.dedup != CspDedupMode::CspDedupOff && self.dedup_count == 0 {
+            return invalid("dedup window must not be empty");
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for CspConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_test() {
+        let config = CspConfig::new()
+            .address(8)
+            .hostname("obc")
+            .model("payload")
+            .revision("v2")
+            .buffers(20, 300)
+            .fifo_length(32);
+        assert_eq!(config.address, 8);
+        assert_eq!(config.hostname, "obc");
+        assert_eq!((config.buffers, config.buffer_data_size), (20, 300));
+        assert_eq!(config.fifo_length, 32);
+        assert_eq!(config.rdp, CspRdpConfig::default());
+        assert!(config.validate().is_ok());
+
+        assert!(CspConfig::new().address(32).validate().is_err());
+        assert!(CspConfig::new().buffers(0, 256).validate().is_err());
+        assert!(CspConfig::new()
+            .rdp(CspRdpConfig {
+                window_size: 0,
+                ..CspRdpConfig::default()
+            })
+            .validate()
+            .is_err());
+        assert!(CspConfig::new().fifo_length(0).validate().is_err());
+        assert!(CspConfig::new().dedup_window(0, 1000).validate().is_ok());
+        assert!(CspConfig::new()
+            .dedup(CspDedupMode::CspDedupAll)
+            .dedup_window(0, 1000)
+            .validate()
+            .is_err());
+        assert!(CspConfig::new()
+            .hostname("a hostname longer than twenty")
+            .validate()
+            .is_err());
+    }
+}
@@ -1,31 +1,276 @@
 // SPDX-License-Identifier: MIT
 
+use crate::csp::csp::CSP;
+use crate::csp::interface::{CspIface, CspIfaceStats};
 use crate::csp::types::*;
-use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub fn csp_conn_init() {
-    info!("CSP conn init");
+/// Highest port that can be bound, ports above it are used as ephemeral source ports
+pub const CSP_MAX_BIND_PORT: u8 = 31;
+pub const CSP_ID_PORT_MAX: u8 = 63;
+
+/**
+ * Socket options, packets not meeting them are dropped by the router. Only CRC32 is verified,
+ * the other options check the header flag alone: a packet claiming HMAC is not authenticated,
+ * so HMACREQ is no protection against forged packets. XTEA is not supported
+ */
+pub const CSP_SO_NONE: u32 = 0x0000;
+pub const CSP_SO_RDPREQ: u32 = 0x0001;
+pub const CSP_SO_RDPPROHIB: u32 = 0x0002;
+pub const CSP_SO_HMACREQ: u32 = 0x0004;
+pub const CSP_SO_HMACPROHIB: u32 = 0x0008;
+pub const CSP_SO_XTEAREQ: u32 = 0x0010;
+pub const CSP_SO_XTEAPROHIB: u32 = 0x0020;
+pub const CSP_SO_CRC32REQ: u32 = 0x0040;
+pub const CSP_SO_CRC32PROHIB: u32 = 0x0080;
+
+/// Required option, prohibited option and header flag of each feature
+const CSP_SO_FLAGS: [(u32, u32, u8, &str); 4] = [
+    (CSP_SO_RDPREQ, CSP_SO_RDPPROHIB, CSP_FRDP, "RDP"),
+    (CSP_SO_HMACREQ, CSP_SO_HMACPROHIB, CSP_FHMAC, "HMAC"),
+    (CSP_SO_XTEAREQ, CSP_SO_XTEAPROHIB, CSP_FXTEA, "XTEA"),
+    (CSP_SO_CRC32REQ, CSP_SO_CRC32PROHIB, CSP_FCRC32, "CRC32"),
+];
+
+/// Connection key: local port, peer address and peer port
+pub(crate) type CspConnKey = (u8, u8, u8);
+
+/// Queue the router fills with the packets of a connection
+#[derive(Clone)]
+pub(crate) enum CspConnTx {
+    /// Read with CspConnection::read, None wakes the reader to route the RX queue itself
+    Sync(SyncSender<Option<CspPacket>>),
+    #[cfg(feature = "tokio")]
+    Async(tokio::sync::mpsc::Sender<CspPacket>),
+}
+
+/// Connection as seen by the router
+pub(crate) struct CspConnEntry {
+    pub tx: CspConnTx,
+    pub last_activity: Instant,
+    pub idle_timeout: Option<Duration>,
+    pub expired: Arc<AtomicBool>,
+    /// Socket options the packets of the connection must meet
+    pub opts: u32,
+    /// Packets dropped for not meeting opts
+    pub drops: Arc<AtomicU32>,
+    /// Set while the reader waits on its queue for someone else to route the RX queue
+    pub waiting: bool,
+}
+
+/// Registration of a connection in the stack, dropping it removes the connection
+pub(crate) struct CspConnSlot {
+    pub csp: CSP,
+    pub key: CspConnKey,
+    /// Set when the connection was closed for being idle
+    pub expired: Arc<AtomicBool>,
+}
+
+/**
+ * Receive queue of a connection opened with CSP::csp_connect, filled by the router with the
+ * packets of the connection. Dropping it removes the connection from the stack
+ */
+pub struct CspConnQueue {
+    pub(crate) slot: CspConnSlot,
+    pub(crate) rx: Receiver<Option<CspPacket>>,
+}
+
+static SPORT_OUTGOING: AtomicU8 = AtomicU8::new(CSP_MAX_BIND_PORT + 1);
+
+pub fn csp_conn_init(conn_max: usize) {
+    info!("CSP conn init ({} connections)", conn_max);
+}
+
+pub(crate) fn csp_conn_sport() -> u8 {
+    SPORT_OUTGOING
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sport| {
+            if sport >= CSP_ID_PORT_MAX {
+                Some(CSP_MAX_BIND_PORT + 1)
+            } else {
+                Some(sport + 1)
+            }
+        })
+        .unwrap_or_else(|sport| sport)
+}
+
+/// Rejects socket options both requiring and prohibiting a feature, or requiring XTEA
+pub fn csp_socket_opts_validate(opts: u32) -> Result<(), CspError> {
+    if opts & CSP_SO_XTEAREQ != 0 {
+        warn!("XTEA not supported");
+        return Err(CspError::CspErrNotSup);
+    }
+    for (req, prohib, _, name) in CSP_SO_FLAGS {
+        if opts & req != 0 && opts & prohib != 0 {
+            warn!("Socket options both require and prohibit {}", name);
+            return Err(CspError::CspErrInval(format!(
+                "{} both required and prohibited",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the options of a socket or connection on an incoming packet, counting the packets
+/// dropped for not meeting them
+pub(crate) fn csp_opts_accept(opts: u32, drops: &AtomicU32, id: &CspId, iface: &CspIface) -> bool {
+    if csp_route_check_options(opts, id) {
+        return true;
+    }
+    CspIfaceStats::inc(&iface.stats.autherr);
+    drops.fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+/// Checks the header flags of an incoming packet against the options of its socket, the CRC32
+/// itself is verified by the router beforehand
+pub fn csp_route_check_options(opts: u32, id: &CspId) -> bool {
+    for (req, prohib, flag, name) in CSP_SO_FLAGS {
+        if opts & req != 0 && id.flags & flag == 0 {
+            debug!("{} required, dropping {:?}", name, id);
+            return false;
+        }
+        if opts & prohib != 0 && id.flags & flag != 0 {
+            debug!("{} prohibited, dropping {:?}", name, id);
+            return false;
+        }
+    }
+    true
 }
 
 pub fn csp_connect(
-    _prio: CspPriorities,
-    _dest: u16,
-    _dport: u8,
+    prio: CspPriorities,
+    dest: u16,
+    dport: u8,
     _timeout: u32,
-    _opts: u8,
-) -> Result<CspConnection, io::Error> {
+    opts: u8,
+) -> Result<CspConnection, CspError> {
+    csp_socket_opts_validate(opts as u32)?;
+    // Packets sent carry a CRC32 when the connection requires it
+    let flags = if opts as u32 & CSP_SO_CRC32REQ != 0 {
+        CSP_FCRC32
+    } else {
+        0
+    };
+
     let a = CspConnection {
-        opts: 0,
-        state: ConnState::ConnClosed,
+        opts: opts as u32,
+        state: ConnState::ConnOpen,
         idout: CspId {
-            pri: 2,
-            flags: 1,
-            src: 5,
-            dst: 12,
-            dport: 23,
-            sport: 99,
+            pri: prio as u8,
+            flags,
+            src: 0,
+            dst: dest as u8,
+            dport,
+            sport: csp_conn_sport(),
         },
+        queue: None,
     };
 
     Ok(a)
 }
+
+impl CspConnTx {
+    /// Queues packet without blocking, fails if the queue is full or its reader is gone
+    pub(crate) fn try_send(&self, packet: CspPacket) -> Result<(), CspError> {
+        let res = match self {
+            CspConnTx::Sync(tx) => tx.try_send(Some(packet)).is_ok(),
+            #[cfg(feature = "tokio")]
+            CspConnTx::Async(tx) => tx.try_send(packet).is_ok(),
+        };
+        if res {
+            Ok(())
+        } else {
+            Err(CspError::CspErrNoBufs)
+        }
+    }
+}
+
+impl CspConnEntry {
+    /// Idle connections expire at the returned instant unless they see traffic
+    pub(crate) fn idle_deadline(&self) -> Option<Instant> {
+        self.idle_timeout.map(|t| self.last_activity + t)
+    }
+
+    pub(crate) fn is_idle(&self, now: Instant) -> bool {
+        self.idle_deadline().is_some_and(|d| now >= d)
+    }
+
+    /// Wakes the reader waiting on the queue of a sync connection
+    pub(crate) fn wake(&mut self) {
+        if !self.waiting {
+            return;
+        }
+        self.waiting = false;
+        match &self.tx {
+            CspConnTx::Sync(tx) => {
+                let _ = tx.try_send(None);
+            }
+            // Async readers never route the RX queue
+            #[cfg(feature = "tokio")]
+            CspConnTx::Async(_) => {}
+        }
+    }
+}
+
+impl CspConnSlot {
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CspConnSlot {
+    fn drop(&mut self) {
+        self.csp.csp_conn_remove(self.key, &self.expired);
+    }
+}
+
+impl CspConnection {
+    /// Waits up to timeout for a packet of this connection. Fails with CspErrTimedOut once
+    /// the connection was closed for being idle
+    pub fn read(&self, timeout: Duration) -> Result<CspPacket, CspError> {
+        if self.state != ConnState::ConnOpen {
+            return Err(CspError::CspErrConnClosed);
+        }
+        match &self.queue {
+            Some(q) => q.slot.csp.csp_conn_read(q, timeout),
+            None => Err(CspError::inval("connection has no receive queue")),
+        }
+    }
+
+    /// Closes the connection, packets for it are no longer queued
+    pub fn close(&mut self) {
+        self.state = ConnState::ConnClosed;
+        self.queue = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_opts_test() {
+        assert!(csp_socket_opts_validate(CSP_SO_HMACREQ | CSP_SO_XTEAPROHIB).is_ok());
+        assert!(csp_socket_opts_validate(CSP_SO_CRC32REQ | CSP_SO_CRC32PROHIB).is_err());
+        assert!(matches!(
+            csp_socket_opts_validate(CSP_SO_XTEAREQ),
+            Err(CspError::CspErrNotSup)
+        ));
+
+        let plain = CspId::new();
+        let hmac = CspId::new().flags(CSP_FHMAC | CSP_FCRC32);
+        assert!(csp_route_check_options(CSP_SO_NONE, &plain));
+        assert!(csp_route_check_options(CSP_SO_NONE, &hmac));
+        assert!(!csp_route_check_options(CSP_SO_HMACREQ, &plain));
+        assert!(csp_route_check_options(CSP_SO_HMACREQ, &hmac));
+        assert!(!csp_route_check_options(CSP_SO_CRC32PROHIB, &hmac));
+        assert!(csp_route_check_options(
+            CSP_SO_RDPPROHIB | CSP_SO_XTEAPROHIB,
+            &hmac
+        ));
+    }
+}
@@ -1,71 +1,940 @@
 // SPDX-License-Identifier: MIT
 
-use std::io;
-use std::sync::mpsc::sync_channel;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{
+    sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError,
+};
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use std::time::{Duration, Instant};
 
+use crate::csp::clock::*;
+use crate::csp::config::CspConfig;
+use crate::csp::conn::*;
+use crate::csp::dedup::*;
+use crate::csp::interface::{CspIfaceStats, NextHop};
+use crate::csp::memmap::CspMemoryMap;
+use crate::csp::rtable::*;
+use crate::csp::sfp::CSP_SFP_HEADER_LEN;
 use crate::csp::types::*;
 
+type CspHook = Box<dyn Fn() + Send + Sync>;
+
+/**
+ * Handle to a CSP stack. Clones are cheap and refer to the same stack, so it can be shared by
+ * application threads. The stack is torn down when the last handle is dropped
+ */
+#[derive(Clone)]
 pub struct CSP {
-    intf_list: Vec<Box<dyn crate::csp::interface::NextHop>>,
-    channel_rx: std::sync::mpsc::Receiver<CspFIFO>,
-    channel_tx: std::sync::mpsc::SyncSender<CspFIFO>,
+    inner: Arc<CspInner>,
+}
+
+struct CspInner {
+    intf_list: RwLock<Vec<Arc<dyn NextHop>>>,
+    channel_rx: Mutex<Receiver<CspFIFO>>,
+    channel_tx: SyncSender<CspFIFO>,
+    reboot_hook: RwLock<Option<CspHook>>,
+    shutdown_hook: RwLock<Option<CspHook>>,
+    rtable: RwLock<CspRtable>,
+    config: CspConfig,
+    memory_map: Mutex<Option<Box<dyn CspMemoryMap>>>,
+    clock: Mutex<Box<dyn CspClock>>,
+    promisc_tx: RwLock<Option<SyncSender<CspPacket>>>,
+    promisc_rx: Mutex<Option<Receiver<CspPacket>>>,
+    dedup: Mutex<CspDedup>,
+    dedup_drops: AtomicU32,
+    split_horizon_drops: AtomicU32,
+    conns: Mutex<HashMap<CspConnKey, CspConnEntry>>,
+    /// Packets taken from the RX queue by a connection reader and claimed by no connection
+    pending: Mutex<VecDeque<CspFIFO>>,
+    /// Signaled when a packet is added to pending or the RX queue is released
+    pending_cv: Condvar,
+    /// Socket options of the ports read with csp_read, and the packets dropped for them
+    port_opts: Mutex<HashMap<u8, (u32, Arc<AtomicU32>)>>,
+}
+
+// A panic while holding a lock leaves data that is still consistent for every use in the
+// stack, so poisoning is ignored instead of propagating the panic to every other thread
+pub(crate) fn csp_lock<T: ?Sized>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn csp_read_lock<T: ?Sized>(l: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    l.read().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn csp_write_lock<T: ?Sized>(l: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    l.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sends packet out of iface, counting it in the interface stats
+pub(crate) fn csp_send_iface(
+    iface: &dyn NextHop,
+    via: u16,
+    packet: &mut CspPacket,
+    from_me: bool,
+) -> Result<(), CspError> {
+    let intf = iface.iface();
+    let stats = &intf.stats;
+    let len = packet.data.len() as u32;
+    if len > intf.mtu as u32 {
+        warn!(
+            "Packet of {} bytes exceeds MTU of {} ({})",
+            len, intf.name, intf.mtu
+        );
+        CspIfaceStats::inc(&stats.oversize);
+        CspIfaceStats::inc(&stats.tx_error);
+        return Err(CspError::CspErrInval(format!(
+            "packet of {} bytes exceeds MTU {} of {}",
+            len, intf.mtu, intf.name
+        )));
+    }
+
+    let res = iface.next_hop(via, packet, from_me);
+    match res {
+        Ok(()) => {
+            CspIfaceStats::inc(&stats.tx);
+            CspIfaceStats::add(&stats.txbytes, len);
+        }
+        Err(_) => CspIfaceStats::inc(&stats.tx_error),
+    }
+    res
 }
 
 impl CSP {
     pub fn new() -> Self {
-        // TODO: Any better style to keep tuple at init time?
-        // TODO: This 16 should be configurable
-        let (a, b) = sync_channel(16);
+        Self::from_config(CspConfig::default())
+    }
+
+    /// Creates the stack with the given configuration, it is rejected if not valid
+    pub fn with_config(config: CspConfig) -> Result<Self, CspError> {
+        config.validate()?;
+        Ok(Self::from_config(config))
+    }
+
+    fn from_config(config: CspConfig) -> Self {
+        info!(
+            "CSP stack for node {} ({})",
+            config.address, config.hostname
+        );
+        let (a, b) = sync_channel(config.fifo_length);
+        let dedup = CspDedup::new(
+            config.dedup_count,
+            Duration::from_millis(config.dedup_window_ms as u64),
+        );
         CSP {
-            intf_list: Vec::new(),
-            channel_tx: a,
-            channel_rx: b,
+            inner: Arc::new(CspInner {
+                intf_list: RwLock::new(Vec::new()),
+                channel_tx: a,
+                channel_rx: Mutex::new(b),
+                reboot_hook: RwLock::new(None),
+                shutdown_hook: RwLock::new(None),
+                rtable: RwLock::new(CspRtable::new()),
+                config,
+                memory_map: Mutex::new(None),
+                clock: Mutex::new(Box::new(CspSystemClock {})),
+                promisc_tx: RwLock::new(None),
+                promisc_rx: Mutex::new(None),
+                dedup: Mutex::new(dedup),
+                dedup_drops: AtomicU32::new(0),
+                split_horizon_drops: AtomicU32::new(0),
+                conns: Mutex::new(HashMap::new()),
+                pending: Mutex::new(VecDeque::new()),
+                pending_cv: Condvar::new(),
+                port_opts: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Registers an interface, names must be unique and the MTU must fit in a buffer
+    pub fn add_interface(&self, intf: Box<dyn NextHop>) -> Result<(), CspError> {
+        let mtu = intf.iface().mtu as usize;
+        if mtu > self.inner.config.buffer_data_size {
+            warn!(
+                "Interface {} MTU {} larger than buffer size {}",
+                intf.name(),
+                mtu,
+                self.inner.config.buffer_data_size
+            );
+            return Err(CspError::inval("interface MTU larger than buffer size"));
+        }
+
+        let mut list = csp_write_lock(&self.inner.intf_list);
+        if list.iter().any(|i| i.name() == intf.name()) {
+            warn!("Interface {} already registered", intf.name());
+            return Err(CspError::CspErrAlready);
+        }
+
+        info!("Adding interface {}", intf.name());
+        list.push(Arc::from(intf));
+        Ok(())
+    }
+
+    /// Registers the application hook run when a reboot request is received
+    pub fn csp_sys_set_reboot(&self, hook: CspHook) {
+        *csp_write_lock(&self.inner.reboot_hook) = Some(hook);
+    }
+
+    /// Registers the application hook run when a shutdown request is received
+    pub fn csp_sys_set_shutdown(&self, hook: CspHook) {
+        *csp_write_lock(&self.inner.shutdown_hook) = Some(hook);
+    }
+
+    pub fn csp_sys_reboot(&self) -> Result<(), CspError> {
+        match &*csp_read_lock(&self.inner.reboot_hook) {
+            Some(hook) => {
+                info!("Rebooting");
+                hook();
+                Ok(())
+            }
+            None => {
+                warn!("No reboot hook registered");
+                Err(CspError::CspErrNotSup)
+            }
+        }
+    }
+
+    pub fn csp_sys_shutdown(&self) -> Result<(), CspError> {
+        match &*csp_read_lock(&self.inner.shutdown_hook) {
+            Some(hook) => {
+                info!("Shutting down");
+                hook();
+                Ok(())
+            }
+            None => {
+                warn!("No shutdown hook registered");
+                Err(CspError::CspErrNotSup)
+            }
         }
     }
 
-    pub fn add_interface(&mut self, intf: Box<dyn crate::csp::interface::NextHop>) {
-        self.intf_list.push(intf);
+    /// Sets the memory served by the CMP peek and poke services
+    pub fn csp_set_memory_map(&self, map: Box<dyn CspMemoryMap>) {
+        *csp_lock(&self.inner.memory_map) = Some(map);
     }
 
-    pub fn get_rx_channel(&self) -> std::sync::mpsc::SyncSender<CspFIFO> {
-        self.channel_tx.clone()
+    pub(crate) fn csp_memory_map(&self) -> MutexGuard<'_, Option<Box<dyn CspMemoryMap>>> {
+        csp_lock(&self.inner.memory_map)
+    }
+
+    /// Replaces the clock served by the CMP clock service, the system clock by default
+    pub fn csp_set_clock(&self, clock: Box<dyn CspClock>) {
+        *csp_lock(&self.inner.clock) = clock;
+    }
+
+    pub fn csp_clock_get_time(&self) -> CspTimestamp {
+        csp_lock(&self.inner.clock).get_time()
+    }
+
+    pub fn csp_clock_set_time(&self, time: &CspTimestamp) -> Result<(), CspError> {
+        csp_lock(&self.inner.clock).set_time(time)
+    }
+
+    pub fn get_rx_channel(&self) -> SyncSender<CspFIFO> {
+        self.inner.channel_tx.clone()
+    }
+
+    pub fn csp_get_config(&self) -> &CspConfig {
+        &self.inner.config
+    }
+
+    pub fn csp_get_address(&self) -> u16 {
+        self.inner.config.address
+    }
+
+    pub fn csp_get_hostname(&self) -> &str {
+        &self.inner.config.hostname
+    }
+
+    pub fn csp_get_model(&self) -> &str {
+        &self.inner.config.model
+    }
+
+    pub fn csp_get_revision(&self) -> &str {
+        &self.inner.config.revision
+    }
+
+    pub fn iflist_get_by_name(&self, name: &str) -> Option<Arc<dyn NextHop>> {
+        csp_read_lock(&self.inner.intf_list)
+            .iter()
+            .find(|i| i.name() == name)
+            .cloned()
+    }
+
+    /// Iterates the registered interfaces in the order they were added
+    pub fn iflist_iter(&self) -> impl Iterator<Item = Arc<dyn NextHop>> {
+        csp_read_lock(&self.inner.intf_list).clone().into_iter()
+    }
+
+    /// Unregisters an interface, routes through it are kept but fail until it is added again
+    pub fn iflist_remove(&self, name: &str) -> Option<Arc<dyn NextHop>> {
+        let mut list = csp_write_lock(&self.inner.intf_list);
+        let pos = list.iter().position(|i| i.name() == name)?;
+        info!("Removing interface {}", name);
+        Some(list.remove(pos))
+    }
+
+    /// Sets route to address/netmask through the interface named iface
+    pub fn csp_rtable_set(
+        &self,
+        address: u16,
+        netmask: u16,
+        iface: &str,
+        via: u16,
+    ) -> Result<(), CspError> {
+        if self.iflist_get_by_name(iface).is_none() {
+            warn!("No interface named {}", iface);
+            return Err(CspError::CspErrInval(format!(
+                "unknown interface {}",
+                iface
+            )));
+        }
+        csp_write_lock(&self.inner.rtable).set(address, netmask, iface, via)
+    }
+
+    /// Returns a copy of the routing table
+    pub fn csp_rtable(&self) -> CspRtable {
+        csp_read_lock(&self.inner.rtable).clone()
+    }
+
+    /// Adds the routes of a table in text format, either all of them are added or none
+    pub fn csp_rtable_load(&self, table: &str) -> Result<usize, CspError> {
+        let routes = CspRtable::parse(table)?;
+
+        if let Some(r) = routes
+            .iter()
+            .find(|r| self.iflist_get_by_name(&r.iface).is_none())
+        {
+            warn!("No interface named {}", r.iface);
+            return Err(CspError::CspErrInval(format!(
+                "route entry '{}': unknown interface {}",
+                r, r.iface
+            )));
+        }
+
+        let mut rtable = csp_write_lock(&self.inner.rtable);
+        for r in &routes {
+            rtable.set(r.address, r.netmask, &r.iface, r.via)?;
+        }
+        Ok(routes.len())
+    }
+
+    pub fn csp_rtable_save(&self) -> String {
+        csp_read_lock(&self.inner.rtable).save()
+    }
+
+    pub fn csp_rtable_clear(&self) {
+        csp_write_lock(&self.inner.rtable).clear();
     }
     pub fn csp_send(
-        self,
+        &self,
         conn: &mut CspConnection,
         packet: &mut CspPacket,
-    ) -> Result<(), io::Error> {
+    ) -> Result<(), CspError> {
         if conn.state != ConnState::ConnOpen {
             warn!("Connection closed");
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Connection Closed",
-            ))?
+            return Err(CspError::CspErrConnClosed);
+        }
+        if let Some(q) = &conn.queue {
+            self.csp_conn_touch(&q.slot)?;
         }
 
         self.csp_send_direct(conn, packet)
     }
 
     pub fn csp_send_direct(
-        self,
-        _conn: &mut CspConnection,
+        &self,
+        conn: &mut CspConnection,
         packet: &mut CspPacket,
-    ) -> Result<(), io::Error> {
+    ) -> Result<(), CspError> {
         let from_me = true;
-        let via = 2u16;
 
-        let iface = &self.intf_list[0];
+        packet.id = conn.idout;
+        packet.id.src = self.inner.config.address as u8;
+        let dst = packet.id.dst as u16;
+        let crc_len = if packet.id.flags & CSP_FCRC32 != 0 {
+            4
+        } else {
+            0
+        };
+
+        match self.csp_route_find(dst) {
+            (Some(i), via) => {
+                let mtu = i.iface().mtu as usize;
+                if self.inner.config.fragmentation
+                    && packet.data.len() + crc_len > mtu
+                    && packet.id.flags & CSP_FFRAG == 0
+                {
+                    debug!("Fragmenting packet of {} bytes", packet.data.len());
+                    let mtu = mtu.saturating_sub(CSP_SFP_HEADER_LEN + crc_len);
+                    return self.csp_sfp_send(conn, &packet.data, mtu);
+                }
+
+                if crc_len > 0 {
+                    packet.csp_crc32_append();
+                }
+                let res = csp_send_iface(i.as_ref(), via, packet, from_me);
+                if res.is_ok() {
+                    self.csp_promisc_add(packet);
+                }
+                res
+            }
+            (None, _) => {
+                warn!("No route to {}", dst);
+                Err(CspError::CspErrNoRoute)
+            }
+        }
+    }
+
+    /// Interface and via address to reach dst, the first interface is the default route
+    pub(crate) fn csp_route_find(&self, dst: u16) -> (Option<Arc<dyn NextHop>>, u16) {
+        let route = csp_read_lock(&self.inner.rtable).find(dst).cloned();
+        match route {
+            Some(route) => (
+                self.iflist_get_by_name(&route.iface),
+                if route.via == CSP_NO_VIA_ADDRESS {
+                    dst
+                } else {
+                    route.via
+                },
+            ),
+            None => (csp_read_lock(&self.inner.intf_list).first().cloned(), dst),
+        }
+    }
+
+    /// Reads the next received packet. Packets addressed to another node are forwarded and
+    /// Ok(None) is returned for them
+    pub fn csp_route_work(&self, timeout: Duration) -> Result<Option<CspPacket>, CspError> {
+        let fifo = self.csp_read_fifo(timeout)?;
+        Ok(self.csp_route_incoming(fifo).map(|p| p.packet))
+    }
+
+    /// Forwards fifo if it is not addressed to this node, otherwise gives it back for delivery
+    pub(crate) fn csp_route_incoming(&self, fifo: CspFIFO) -> Option<CspFIFO> {
+        let dst = fifo.packet.id.dst as u16;
+        if dst == self.inner.config.address || dst == CSP_BROADCAST_ADDR {
+            return Some(fifo);
+        }
+
+        if let Err(e) = self.csp_route_forward(fifo) {
+            debug!("Not forwarding packet to {}: {}", dst, e);
+        }
+        None
+    }
+
+    fn csp_route_forward(&self, mut fifo: CspFIFO) -> Result<(), CspError> {
+        let dst = fifo.packet.id.dst as u16;
+        let (iface, via) = self.csp_route_find(dst);
+        let iface = match iface {
+            Some(i) => i,
+            None => {
+                CspIfaceStats::inc(&fifo.iface.stats.drop);
+                return Err(CspError::CspErrNoRoute);
+            }
+        };
+
+        // Split horizon, never send a packet back where it came from
+        let out = iface.iface();
+        if out.name == fifo.iface.name && out.split_horizon_off == 0 {
+            CspIfaceStats::inc(&fifo.iface.stats.drop);
+            self.inner
+                .split_horizon_drops
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(CspError::inval(
+                "route leads back to the incoming interface",
+            ));
+        }
+
+        csp_send_iface(iface.as_ref(), via, &mut fifo.packet, false)
+    }
+
+    /// Number of packets not forwarded because their route led back to the incoming interface
+    pub fn csp_split_horizon_drops(&self) -> u32 {
+        self.inner.split_horizon_drops.load(Ordering::Relaxed)
+    }
+
+    pub fn csp_read(&self, timeout: Duration) -> Result<CspPacket, CspError> {
+        self.csp_read_fifo(timeout).map(|p| p.packet)
+    }
+
+    /// Reads the next received packet along with the interface it came from, duplicates are
+    /// discarded
+    pub(crate) fn csp_read_fifo(&self, timeout: Duration) -> Result<CspFIFO, CspError> {
+        self.csp_conn_check_timeouts();
+        let res = self.csp_read_rx_queue(timeout);
+        self.csp_rx_release();
+        res
+    }
+
+    /// Takes the next packet of the RX queue as the interface received it, without duplicate
+    /// check, CRC32 verification or delivery to connections
+    pub(crate) fn csp_read_raw_fifo(&self, timeout: Duration) -> Result<CspFIFO, CspError> {
+        let res = csp_lock(&self.inner.channel_rx).recv_timeout(timeout);
+        self.csp_rx_release();
+        res.map_err(|e| match e {
+            RecvTimeoutError::Timeout => CspError::CspErrTimedOut,
+            RecvTimeoutError::Disconnected => CspError::CspErrReset,
+        })
+    }
+
+    fn csp_read_rx_queue(&self, timeout: Duration) -> Result<CspFIFO, CspError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut pending = csp_lock(&self.inner.pending);
+            if let Some(p) = pending.pop_front() {
+                return Ok(p);
+            }
+            let channel_rx = match self.inner.channel_rx.try_lock() {
+                Ok(c) => c,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                // A connection reader routes, it queues our packets or wakes us up when done
+                Err(TryLockError::WouldBlock) => {
+                    if remaining.is_zero() {
+                        return Err(CspError::CspErrTimedOut);
+                    }
+                    let _wait = self
+                        .inner
+                        .pending_cv
+                        .wait_timeout(pending, remaining)
+                        .unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+            };
+            drop(pending);
+            let pkt = channel_rx.recv_timeout(remaining);
+            drop(channel_rx);
+            match pkt {
+                Ok(p) => {
+                    if let Some(p) = self.csp_rx_incoming(p) {
+                        return Ok(p);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Err(CspError::CspErrTimedOut),
+                Err(RecvTimeoutError::Disconnected) => return Err(CspError::CspErrReset),
+            }
+        }
+    }
+
+    /// Handles a packet taken from the RX queue: promiscuous copy, duplicate check, CRC32 and
+    /// delivery to its connection. Returns the packet if no connection claimed it and it meets
+    /// the options of its port
+    fn csp_rx_incoming(&self, mut p: CspFIFO) -> Option<CspFIFO> {
+        // Idle connections must not receive this packet
+        self.csp_conn_check_timeouts();
+        self.csp_promisc_add(&p.packet);
+        if self.csp_dedup_is_duplicate(&p.packet) {
+            debug!("Discarding duplicate packet {:?}", p.packet.id);
+            CspIfaceStats::inc(&p.iface.stats.drop);
+            self.inner.dedup_drops.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        // Forwarded packets keep their CRC32 for the node they are addressed to
+        let dst = p.packet.id.dst as u16;
+        let local = dst == self.inner.config.address || dst == CSP_BROADCAST_ADDR;
+        if local && p.packet.id.flags & CSP_FCRC32 != 0 && p.packet.csp_crc32_verify().is_err() {
+            warn!("CRC32 mismatch, dropping packet {:?}", p.packet.id);
+            CspIfaceStats::inc(&p.iface.stats.rx_error);
+            return None;
+        }
+
+        let p = self.csp_conn_deliver(p)?;
+        if local && !self.csp_port_accepts(&p) {
+            return None;
+        }
+        Some(p)
+    }
+
+    /// Sets the CSP_SO_* socket options packets read with csp_read on port must meet, the
+    /// others are dropped. CSP_SO_NONE accepts every packet
+    pub fn csp_port_set_opts(&self, port: u8, opts: u32) -> Result<(), CspError> {
+        if port > CSP_MAX_BIND_PORT {
+            warn!("Cannot bind port {}", port);
+            return Err(CspError::CspErrInval(format!("port {} out of range", port)));
+        }
+        csp_socket_opts_validate(opts)?;
+        let mut port_opts = csp_lock(&self.inner.port_opts);
+        if opts == CSP_SO_NONE {
+            port_opts.remove(&port);
+        } else {
+            port_opts
+                .entry(port)
+                .or_insert_with(|| (opts, Arc::new(AtomicU32::new(0))))
+                .0 = opts;
+        }
+        Ok(())
+    }
+
+    /// Number of packets to port dropped for not meeting its socket options
+    pub fn csp_port_drops(&self, port: u8) -> u32 {
+        csp_lock(&self.inner.port_opts)
+            .get(&port)
+            .map_or(0, |(_, drops)| drops.load(Ordering::Relaxed))
+    }
+
+    fn csp_port_accepts(&self, p: &CspFIFO) -> bool {
+        match csp_lock(&self.inner.port_opts).get(&p.packet.id.dport) {
+            Some((opts, drops)) => csp_opts_accept(*opts, drops, &p.packet.id, &p.iface),
+            None => true,
+        }
+    }
+
+    fn csp_dedup_is_duplicate(&self, packet: &CspPacket) -> bool {
+        let check = match self.inner.config.dedup {
+            CspDedupMode::CspDedupOff => false,
+            // Broadcasts are delivered locally too
+            CspDedupMode::CspDedupForward => {
+                let dst = packet.id.dst as u16;
+                dst != self.inner.config.address && dst != CSP_BROADCAST_ADDR
+            }
+            CspDedupMode::CspDedupAll => true,
+        };
+        check && csp_lock(&self.inner.dedup).is_duplicate(packet, Instant::now())
+    }
+
+    /// Number of received packets discarded as duplicates
+    pub fn csp_dedup_drops(&self) -> u32 {
+        self.inner.dedup_drops.load(Ordering::Relaxed)
+    }
+
+    /// Opens a connection to dport of dest, the packets it receives are queued to it. The
+    /// connection is closed after timeout ms without traffic, 0 uses the configured idle timeout
+    pub fn csp_connect(
+        &self,
+        prio: CspPriorities,
+        dest: u16,
+        dport: u8,
+        timeout: u32,
+        opts: u8,
+    ) -> Result<CspConnection, CspError> {
+        let mut conn = csp_connect(prio, dest, dport, timeout, opts)?;
+        let (tx, rx) = sync_channel(self.inner.config.conn_queue_length);
+        let slot = self.csp_conn_open(&mut conn.idout, &CspConnTx::Sync(tx), timeout, conn.opts)?;
+        conn.queue = Some(CspConnQueue { slot, rx });
+        Ok(conn)
+    }
+
+    /// Registers an outgoing connection, its source port is changed if it is already used with
+    /// the same peer
+    pub(crate) fn csp_conn_open(
+        &self,
+        idout: &mut CspId,
+        tx: &CspConnTx,
+        timeout: u32,
+        opts: u32,
+    ) -> Result<CspConnSlot, CspError> {
+        // Source ports are shared, skip the ones in use with the same peer
+        for _ in CSP_MAX_BIND_PORT..CSP_ID_PORT_MAX {
+            let key = (idout.sport, idout.dst, idout.dport);
+            match self.csp_conn_register(key, tx, timeout, opts, Arc::new(AtomicU32::new(0))) {
+                Err(CspError::CspErrUsed) => idout.sport = csp_conn_sport(),
+                res => return res,
+            }
+        }
+
+        warn!("No free source port to {}:{}", idout.dst, idout.dport);
+        Err(CspError::CspErrUsed)
+    }
+
+    /// Adds the connection of key, at most conn_max are open. It is closed after timeout ms
+    /// without traffic, 0 uses the configured idle timeout
+    pub(crate) fn csp_conn_register(
+        &self,
+        key: CspConnKey,
+        tx: &CspConnTx,
+        timeout: u32,
+        opts: u32,
+        drops: Arc<AtomicU32>,
+    ) -> Result<CspConnSlot, CspError> {
+        let mut conns = csp_lock(&self.inner.conns);
+        csp_conn_sweep(&mut conns, Instant::now());
+        if conns.len() >= self.inner.config.conn_max {
+            warn!("No free connections ({})", conns.len());
+            return Err(CspError::CspErrNoBufs);
+        }
+        if conns.contains_key(&key) {
+            return Err(CspError::CspErrUsed);
+        }
+
+        let idle_timeout = match timeout {
+            0 => self.inner.config.conn_idle_timeout_ms,
+            t => t,
+        };
+        let expired = Arc::new(AtomicBool::new(false));
+        conns.insert(
+            key,
+            CspConnEntry {
+                tx: tx.clone(),
+                last_activity: Instant::now(),
+                idle_timeout: match idle_timeout {
+                    0 => None,
+                    t => Some(Duration::from_millis(t as u64)),
+                },
+                expired: expired.clone(),
+                opts,
+                drops,
+                waiting: false,
+            },
+        );
+        Ok(CspConnSlot {
+            csp: self.clone(),
+            key,
+            expired,
+        })
+    }
+
+    /// Number of open connections, sync and async
+    pub fn csp_conn_count(&self) -> usize {
+        csp_lock(&self.inner.conns).len()
+    }
+
+    /// Removes the connection of key, unless it expired and the key was reused
+    pub(crate) fn csp_conn_remove(&self, key: CspConnKey, expired: &Arc<AtomicBool>) {
+        let mut conns = csp_lock(&self.inner.conns);
+        if let Some(entry) = conns.get(&key) {
+            if Arc::ptr_eq(&entry.expired, expired) {
+                conns.remove(&key);
+            }
+        }
+    }
+
+    /// Records traffic on a connection, fails if it was closed for being idle
+    pub(crate) fn csp_conn_touch(&self, slot: &CspConnSlot) -> Result<(), CspError> {
+        let now = Instant::now();
+        let mut conns = csp_lock(&self.inner.conns);
+        csp_conn_sweep(&mut conns, now);
+        match conns.get_mut(&slot.key) {
+            Some(entry) if Arc::ptr_eq(&entry.expired, &slot.expired) => {
+                entry.last_activity = now;
+                Ok(())
+            }
+            _ => {
+                warn!("Connection closed after being idle");
+                Err(CspError::CspErrConnClosed)
+            }
+        }
+    }
+
+    /// Closes the connections idle for longer than their timeout, dropping their queue so their
+    /// readers get CspErrTimedOut
+    pub fn csp_conn_check_timeouts(&self) {
+        csp_conn_sweep(&mut csp_lock(&self.inner.conns), Instant::now());
+    }
+
+    /// Queues p to its connection, returns it if there is none
+    fn csp_conn_deliver(&self, p: CspFIFO) -> Option<CspFIFO> {
+        let id = p.packet.id;
+        if id.dst as u16 != self.inner.config.address {
+            return Some(p);
+        }
+
+        let mut conns = csp_lock(&self.inner.conns);
+        let entry = match conns.get_mut(&(id.dport, id.src, id.sport)) {
+            Some(entry) => entry,
+            None => return Some(p),
+        };
+        if !csp_opts_accept(entry.opts, &entry.drops, &id, &p.iface) {
+            return None;
+        }
+        entry.last_activity = Instant::now();
+        match entry.tx.try_send(p.packet) {
+            Ok(()) => entry.waiting = false,
+            Err(_) => {
+                debug!("Connection queue full, dropping packet {:?}", id);
+                CspIfaceStats::inc(&p.iface.stats.drop);
+            }
+        }
+        None
+    }
+
+    /// Marks the reader of a connection as waiting on its queue or not, returns when the
+    /// connection expires if it sees no traffic
+    fn csp_conn_waiting(&self, slot: &CspConnSlot, waiting: bool) -> Option<Instant> {
+        let mut conns = csp_lock(&self.inner.conns);
+        let entry = conns
+            .get_mut(&slot.key)
+            .filter(|entry| Arc::ptr_eq(&entry.expired, &slot.expired))?;
+        entry.waiting = waiting;
+        entry.idle_deadline()
+    }
+
+    /// Wakes the connection readers waiting for someone to route the RX queue
+    fn csp_conn_wake(&self) {
+        for entry in csp_lock(&self.inner.conns).values_mut() {
+            entry.wake();
+        }
+    }
+
+    /// Waits for a packet on the queue of a connection. While no one else reads the RX queue
+    /// the reader routes it itself, packets of other connections go to their queue and the
+    /// remaining ones are kept for csp_read
+    pub(crate) fn csp_conn_read(
+        &self,
+        q: &CspConnQueue,
+        timeout: Duration,
+    ) -> Result<CspPacket, CspError> {
+        let res = self.csp_conn_wait(q, timeout);
+        self.csp_conn_waiting(&q.slot, false);
+        self.csp_rx_release();
+        res
+    }
+
+    /// Hands the RX queue over to the readers waiting for its holder to route it
+    fn csp_rx_release(&self) {
+        self.csp_conn_wake();
+        let _pending = csp_lock(&self.inner.pending);
+        self.inner.pending_cv.notify_all();
+    }
+
+    fn csp_conn_wait(&self, q: &CspConnQueue, timeout: Duration) -> Result<CspPacket, CspError> {
+        let closed = || {
+            if q.slot.is_expired() {
+                CspError::CspErrTimedOut
+            } else {
+                CspError::CspErrConnClosed
+            }
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.csp_conn_check_timeouts();
+            // Set before looking at the queues, so a reader releasing the RX queue from now on
+            // wakes us up. Closing the connection drops its queue
+            let expiry = self.csp_conn_waiting(&q.slot, true);
+            match q.rx.try_recv() {
+                Ok(Some(p)) => return Ok(p),
+                Ok(None) | Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(closed()),
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(CspError::CspErrTimedOut);
+            }
+            // Wake up when the connection expires to close it
+            let wait = expiry
+                .map_or(deadline, |expiry| expiry.min(deadline))
+                .saturating_duration_since(now);
+
+            let channel_rx = match self.inner.channel_rx.try_lock() {
+                Ok(c) => Some(c),
+                Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+                Err(TryLockError::WouldBlock) => None,
+            };
+            match channel_rx {
+                Some(channel_rx) => {
+                    self.csp_conn_waiting(&q.slot, false);
+                    let pkt = channel_rx.recv_timeout(wait);
+                    drop(channel_rx);
+                    match pkt {
+                        Ok(p) => {
+                            if let Some(p) = self.csp_rx_incoming(p) {
+                                self.csp_pending_add(p);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return Err(CspError::CspErrReset),
+                    }
+                }
+                // Someone else routes, it queues our packets or wakes us up when it is done
+                None => match q.rx.recv_timeout(wait) {
+                    Ok(Some(p)) => return Ok(p),
+                    Ok(None) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return Err(closed()),
+                },
+            }
+        }
+    }
 
-        iface.next_hop(via, packet, from_me)
+    fn csp_pending_add(&self, p: CspFIFO) {
+        let mut pending = csp_lock(&self.inner.pending);
+        if pending.len() >= self.inner.config.fifo_length {
+            if let Some(old) = pending.pop_front() {
+                debug!("Pending queue full, dropping packet {:?}", old.packet.id);
+                CspIfaceStats::inc(&old.iface.stats.drop);
+            }
+        }
+        pending.push_back(p);
+        self.inner.pending_cv.notify_all();
     }
 
-    pub fn csp_read(self, timeout: Duration) -> Result<CspPacket, CspError> {
-        let pkt = self.channel_rx.recv_timeout(timeout);
-        match pkt {
-            Ok(p) => Ok(p.packet),
-            Err(_) => Err(crate::csp::types::CspError::CspNoPacket),
+    /// Starts copying every packet sent or received to a queue of queue_len packets, fails if
+    /// promiscuous mode is already enabled
+    pub fn csp_promisc_enable(&self, queue_len: usize) -> Result<(), CspError> {
+        let mut promisc_tx = csp_write_lock(&self.inner.promisc_tx);
+        if promisc_tx.is_some() {
+            warn!("Promiscuous mode already enabled");
+            return Err(CspError::CspErrAlready);
         }
+
+        info!("Promiscuous mode enabled ({} packets)", queue_len);
+        let (tx, rx) = sync_channel(queue_len);
+        *promisc_tx = Some(tx);
+        *csp_lock(&self.inner.promisc_rx) = Some(rx);
+        Ok(())
+    }
+
+    /// Stops promiscuous mode, packets still queued are discarded
+    pub fn csp_promisc_disable(&self) {
+        *csp_write_lock(&self.inner.promisc_tx) = None;
+        *csp_lock(&self.inner.promisc_rx) = None;
+    }
+
+    pub fn csp_promisc_read(&self, timeout: Duration) -> Result<CspPacket, CspError> {
+        match &*csp_lock(&self.inner.promisc_rx) {
+            Some(rx) => rx
+                .recv_timeout(timeout)
+                .map_err(|_| CspError::CspErrTimedOut),
+            None => Err(CspError::CspErrNotSup),
+        }
+    }
+
+    /// Tears down the stack, closing and removing every interface. Also done when the last
+    /// handle is dropped
+    pub fn shutdown(&self) {
+        self.inner.shutdown();
+    }
+
+    fn csp_promisc_add(&self, packet: &CspPacket) {
+        if let Some(tx) = &*csp_read_lock(&self.inner.promisc_tx) {
+            if let Err(TrySendError::Full(_)) = tx.try_send(packet.clone()) {
+                debug!("Promiscuous queue full, dropping packet {:?}", packet.id);
+            }
+        }
+    }
+}
+
+/// Closes the connections idle at now, dropping their queue
+fn csp_conn_sweep(conns: &mut HashMap<CspConnKey, CspConnEntry>, now: Instant) {
+    conns.retain(|key, entry| {
+        if !entry.is_idle(now) {
+            return true;
+        }
+        info!("Closing idle connection {:?}", key);
+        entry.expired.store(true, Ordering::Relaxed);
+        false
+    });
+}
+
+impl CspInner {
+    fn shutdown(&self) {
+        csp_lock(&self.conns).clear();
+        csp_lock(&self.pending).clear();
+        let intf_list: Vec<Arc<dyn NextHop>> = csp_write_lock(&self.intf_list).drain(..).collect();
+        let promisc = csp_write_lock(&self.promisc_tx).take();
+        if intf_list.is_empty() && promisc.is_none() {
+            return;
+        }
+
+        info!("CSP shutdown");
+        for intf in intf_list {
+            debug!("Closing interface {}", intf.name());
+            intf.close();
+        }
+        csp_write_lock(&self.rtable).clear();
+        *csp_lock(&self.promisc_rx) = None;
     }
 }
 
@@ -74,6 +943,13 @@ impl Default for CSP {
         Self::new()
     }
 }
+
+impl Drop for CspInner {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,14 +957,528 @@ mod tests {
     use crate::csp::interfaces::if_kiss::*;
     use serialport::{DataBits, StopBits};
 
+    struct SinkIntf {
+        intf: CspIface,
+    }
+
+    impl NextHop for SinkIntf {
+        fn next_hop(
+            &self,
+            _via: u16,
+            _packet: &mut CspPacket,
+            _from_me: bool,
+        ) -> Result<(), CspError> {
+            Ok(())
+        }
+
+        fn iface(&self) -> &CspIface {
+            &self.intf
+        }
+    }
+
+    #[test]
+    fn config_test() {
+        assert!(CSP::with_config(CspConfig::new().address(40)).is_err());
+
+        let csp = CSP::with_config(CspConfig::new().address(7).hostname("obc")).unwrap();
+        assert_eq!(csp.csp_get_address(), 7);
+        assert_eq!(csp.csp_get_hostname(), "obc");
+
+        let small = CSP::with_config(CspConfig::new().buffers(10, 100)).unwrap();
+        assert!(small
+            .add_interface(Box::new(SinkIntf {
+                intf: CspIface::new(7, 5, "SINK".to_string()),
+            }))
+            .is_err());
+
+        csp.add_interface(Box::new(SinkIntf {
+            intf: CspIface::new(7, 5, "SINK".to_string()),
+        }))
+        .unwrap();
+        csp.csp_promisc_enable(1).unwrap();
+        let mut conn = CspConnection::new();
+        conn.state = ConnState::ConnOpen;
+        conn.idout = CspId::new().dst(9);
+        csp.csp_send(&mut conn, &mut CspPacket::new()).unwrap();
+        let sent = csp.csp_promisc_read(Duration::from_millis(10)).unwrap();
+        assert_eq!(sent.id.src, 7);
+    }
+
+    #[test]
+    fn iflist_test() {
+        let csp = CSP::new();
+        for name in ["KISS0", "UDP"] {
+            csp.add_interface(Box::new(SinkIntf {
+                intf: CspIface::new(1, 5, name.to_string()),
+            }))
+            .unwrap();
+        }
+        assert!(matches!(
+            csp.add_interface(Box::new(SinkIntf {
+                intf: CspIface::new(2, 5, "UDP".to_string()),
+            })),
+            Err(CspError::CspErrAlready)
+        ));
+
+        let names: Vec<String> = csp.iflist_iter().map(|i| i.name().to_string()).collect();
+        assert_eq!(names, vec!["KISS0", "UDP"]);
+        assert_eq!(csp.iflist_get_by_name("UDP").unwrap().iface().addr, 1);
+        assert!(csp.iflist_get_by_name("CAN").is_none());
+
+        assert!(csp.iflist_remove("CAN").is_none());
+        assert_eq!(csp.iflist_remove("KISS0").unwrap().name(), "KISS0");
+        assert!(csp.iflist_get_by_name("KISS0").is_none());
+        assert_eq!(csp.iflist_iter().count(), 1);
+    }
+
+    #[test]
+    fn rtable_load_test() {
+        let csp = CSP::new();
+        for name in ["KISS", "CAN"] {
+            csp.add_interface(Box::new(SinkIntf {
+                intf: CspIface::new(1, 5, name.to_string()),
+            }))
+            .unwrap();
+        }
+
+        let err = csp.csp_rtable_load("0/0 CAN, 10/2 I2C 10").unwrap_err();
+        assert!(err.to_string().contains("I2C"));
+        assert_eq!(csp.csp_rtable_save(), "");
+
+        assert_eq!(
+            csp.csp_rtable_load("0/0 CAN, 8 KISS, 10/3 KISS 9").unwrap(),
+            3
+        );
+        assert_eq!(csp.csp_rtable_save(), "0/0 CAN, 8/5 KISS, 10/3 KISS 9");
+        assert_eq!(csp.csp_rtable().find(11).unwrap().via, 9);
+
+        assert!(matches!(
+            csp.csp_rtable_set(8, 6, "KISS", CSP_NO_VIA_ADDRESS),
+            Err(CspError::CspErrInval(_))
+        ));
+        assert_eq!(csp.csp_rtable_save(), "0/0 CAN, 8/5 KISS, 10/3 KISS 9");
+
+        csp.csp_rtable_clear();
+        assert!(csp.csp_rtable().find(8).is_none());
+    }
+
+    #[test]
+    fn shared_handle_test() {
+        let csp = CSP::new();
+        csp.add_interface(Box::new(SinkIntf {
+            intf: CspIface::new(1, 5, "SINK".to_string()),
+        }))
+        .unwrap();
+
+        let reader = {
+            let csp = csp.clone();
+            std::thread::spawn(move || {
+                (0..4)
+                    .map(|_| csp.csp_read(Duration::from_secs(1)).unwrap().id.sport)
+                    .sum::<u8>()
+            })
+        };
+
+        let senders: Vec<_> = (0..4)
+            .map(|n| {
+                let csp = csp.clone();
+                std::thread::spawn(move || {
+                    let mut conn = CspConnection::new();
+                    conn.state = ConnState::ConnOpen;
+                    conn.idout = CspId::new().dst(9);
+                    csp.csp_send(&mut conn, &mut CspPacket::new().data(vec![n]))
+                        .unwrap();
+                    csp.get_rx_channel()
+                        .send(CspFIFO {
+                            iface: CspIface::new(1, 5, "SINK".to_string()),
+                            packet: CspPacket::new().id(CspId::new().sport(n)),
+                        })
+                        .unwrap();
+                })
+            })
+            .collect();
+        for s in senders {
+            s.join().unwrap();
+        }
+
+        assert_eq!(reader.join().unwrap(), 1 + 2 + 3);
+        let stats = csp
+            .iflist_get_by_name("SINK")
+            .unwrap()
+            .iface()
+            .stats
+            .snapshot();
+        assert_eq!(stats.tx, 4);
+    }
+
+    #[test]
+    fn conn_read_test() {
+        let csp = CSP::with_config(CspConfig::new().address(1).conn_max(2)).unwrap();
+        csp.add_interface(Box::new(SinkIntf {
+            intf: CspIface::new(1, 5, "SINK".to_string()),
+        }))
+        .unwrap();
+
+        let timeout = Duration::from_millis(500);
+        let prio = || CspPriorities::CspPrioNormal;
+        let mut conn1 = csp.csp_connect(prio(), 9, 10, 0, 0).unwrap();
+        let conn2 = csp.csp_connect(prio(), 9, 10, 0, 0).unwrap();
+        assert_ne!(conn1.idout.sport, conn2.idout.sport);
+        assert!(matches!(
+            csp.csp_connect(prio(), 9, 11, 0, 0),
+            Err(CspError::CspErrNoBufs)
+        ));
+
+        let rx_channel = csp.get_rx_channel();
+        let reply = |conn: &CspConnection, data: u8| CspFIFO {
+            iface: CspIface::new(1, 5, "SINK".to_string()),
+            packet: CspPacket::new()
+                .id(CspId::new().src(9).dst(1).sport(10).dport(conn.idout.sport))
+                .data(vec![data]),
+        };
+        rx_channel.send(reply(&conn2, 2)).unwrap();
+        rx_channel
+            .send(CspFIFO {
+                iface: CspIface::new(1, 5, "SINK".to_string()),
+                packet: CspPacket::new().id(CspId::new().dst(1).dport(20)),
+            })
+            .unwrap();
+        rx_channel.send(reply(&conn1, 1)).unwrap();
+
+        assert_eq!(conn1.read(timeout).unwrap().data, vec![1]);
+        assert_eq!(conn2.read(timeout).unwrap().data, vec![2]);
+        assert_eq!(csp.csp_read(timeout).unwrap().id.dport, 20);
+
+        // A blocked csp_read routes the replies of connections
+        let reader = {
+            let csp = csp.clone();
+            std::thread::spawn(move || csp.csp_read(Duration::from_millis(300)))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        rx_channel.send(reply(&conn2, 3)).unwrap();
+        assert_eq!(conn2.read(timeout).unwrap().data, vec![3]);
+        assert!(reader.join().unwrap().is_err());
+
+        conn1.close();
+        assert!(matches!(
+            conn1.read(timeout),
+            Err(CspError::CspErrConnClosed)
+        ));
+        assert_eq!(csp.csp_conn_count(), 1);
+        drop(conn2);
+        assert_eq!(csp.csp_conn_count(), 0);
+        let mut conn = CspConnection::new();
+        conn.state = ConnState::ConnOpen;
+        assert!(matches!(conn.read(timeout), Err(CspError::CspErrInval(_))));
+    }
+
+    #[test]
+    fn conn_idle_timeout_test() {
+        let config = CspConfig::new().conn_max(1).conn_idle_timeout(50);
+        let csp = CSP::with_config(config).unwrap();
+        csp.add_interface(Box::new(SinkIntf {
+            intf: CspIface::new(1, 5, "SINK".to_string()),
+        }))
+        .unwrap();
+
+        let prio = || CspPriorities::CspPrioNormal;
+        let mut conn = csp.csp_connect(prio(), 9, 10, 0, 0).unwrap();
+        let start = Instant::now();
+        assert!(matches!(
+            conn.read(Duration::from_secs(1)),
+            Err(CspError::CspErrTimedOut)
+        ));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(csp.csp_conn_count(), 0);
+        assert!(matches!(
+            csp.csp_send(&mut conn, &mut CspPacket::new()),
+            Err(CspError::CspErrConnClosed)
+        ));
+
+        // The slot was returned, traffic keeps the new connection open
+        let mut conn = csp.csp_connect(prio(), 9, 10, 200, 0).unwrap();
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(100));
+            csp.csp_send(&mut conn, &mut CspPacket::new()).unwrap();
+        }
+        csp.csp_conn_check_timeouts();
+        assert_eq!(csp.csp_conn_count(), 1);
+        drop(conn);
+
+        // Dropping an expired connection leaves the others alone
+        let conn = csp.csp_connect(prio(), 9, 10, 20, 0).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        csp.csp_conn_check_timeouts();
+        let conn2 = csp.csp_connect(prio(), 9, 10, 0, 0).unwrap();
+        drop(conn);
+        assert_eq!(csp.csp_conn_count(), 1);
+        drop(conn2);
+        assert_eq!(csp.csp_conn_count(), 0);
+
+        // Sending closes an expired connection instead of keeping it open
+        let mut conn = csp.csp_connect(prio(), 9, 10, 20, 0).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(matches!(
+            csp.csp_send(&mut conn, &mut CspPacket::new()),
+            Err(CspError::CspErrConnClosed)
+        ));
+        assert_eq!(csp.csp_conn_count(), 0);
+    }
+
+    #[test]
+    fn conn_read_handoff_test() {
+        let csp = CSP::with_config(CspConfig::new().address(1)).unwrap();
+        csp.add_interface(Box::new(SinkIntf {
+            intf: CspIface::new(1, 5, "SINK".to_string()),
+        }))
+        .unwrap();
+
+        // One reader routes the RX queue, the other one takes over when it returns
+        let prio = || CspPriorities::CspPrioNormal;
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let conn = csp.csp_connect(prio(), 9, 10, 0, 0).unwrap();
+                let sport = conn.idout.sport;
+                let reader = std::thread::spawn(move || conn.read(Duration::from_secs(2)));
+                (sport, reader)
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        let rx_channel = csp.get_rx_channel();
+        for (n, (sport, _)) in readers.iter().enumerate() {
+            rx_channel
+                .send(CspFIFO {
+                    iface: CspIface::new(1, 5, "SINK".to_string()),
+                    packet: CspPacket::new()
+                        .id(CspId::new().src(9).dst(1).sport(10).dport(*sport))
+                        .data(vec![n as u8]),
+                })
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        for (n, (_, reader)) in readers.into_iter().enumerate() {
+            assert_eq!(reader.join().unwrap().unwrap().data, vec![n as u8]);
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn socket_opts_test() {
+        let csp = CSP::with_config(CspConfig::new().address(1)).unwrap();
+        let iface = CspIface::new(1, 5, "SINK".to_string());
+        csp.add_interface(Box::new(SinkIntf {
+            intf: iface.clone(),
+        }))
+        .unwrap();
+        assert!(csp.csp_port_set_opts(40, CSP_SO_HMACREQ).is_err());
+        assert!(csp.csp_port_set_opts(10, CSP_SO_XTEAREQ).is_err());
+        csp.csp_port_set_opts(10, CSP_SO_HMACREQ).unwrap();
+        csp.csp_port_set_opts(11, CSP_SO_CRC32REQ).unwrap();
+
+        let timeout = Duration::from_millis(100);
+        let rx_channel = csp.get_rx_channel();
+        let send = |id: CspId, crc: bool| {
+            let mut packet = CspPacket::new().id(id).data(vec![1, 2]);
+            if crc {
+                packet.csp_crc32_append();
+            }
+            rx_channel
+                .send(CspFIFO {
+                    iface: iface.clone(),
+                    packet,
+                })
+                .unwrap();
+        };
+        send(CspId::new().src(9).dst(1).dport(10), false);
+        send(CspId::new().src(9).dst(1).dport(10).flags(CSP_FHMAC), false);
+        assert_eq!(csp.csp_read(timeout).unwrap().id.flags, CSP_FHMAC);
+        assert_eq!(csp.csp_port_drops(10), 1);
+
+        // The CRC32 is verified and removed
+        send(CspId::new().src(9).dst(1).dport(11), false);
+        send(
+            CspId::new().src(9).dst(1).dport(11).flags(CSP_FCRC32),
+            false,
+        );
+        send(CspId::new().src(9).dst(1).dport(11).flags(CSP_FCRC32), true);
+        assert_eq!(csp.csp_read(timeout).unwrap().data, vec![1, 2]);
+        assert!(csp.csp_read(timeout).is_err());
+        assert_eq!(csp.csp_port_drops(11), 1);
+        let stats = iface.stats.snapshot();
+        assert_eq!((stats.autherr, stats.rx_error), (2, 1));
+
+        // Connections check the replies against their options and add the CRC32 they require
+        csp.csp_promisc_enable(4).unwrap();
+        let mut conn = csp
+            .csp_connect(
+                CspPriorities::CspPrioNormal,
+                9,
+                20,
+                0,
+                CSP_SO_CRC32REQ as u8,
+            )
+            .unwrap();
+        csp.csp_send(&mut conn, &mut CspPacket::new().data(vec![3]))
+            .unwrap();
+        let mut sent = csp.csp_promisc_read(timeout).unwrap();
+        sent.csp_crc32_verify().unwrap();
+        assert_eq!(sent.data, vec![3]);
+
+        let reply = CspId::new().src(9).dst(1).sport(20).dport(conn.idout.sport);
+        send(reply, false);
+        send(reply.flags(CSP_FCRC32), true);
+        assert_eq!(conn.read(timeout).unwrap().data, vec![1, 2]);
+        assert!(conn.read(timeout).is_err());
+        assert_eq!(iface.stats.snapshot().autherr, 3);
+    }
+
+    #[test]
+    fn dedup_test() {
+        let timeout = Duration::from_millis(10);
+        let iface = CspIface::new(1, 5, "SINK".to_string());
+        let incoming = |dst: u8| CspFIFO {
+            iface: iface.clone(),
+            packet: CspPacket::new().id(CspId::new().dst(dst)).data(vec![1, 2]),
+        };
+
+        let csp = CSP::new();
+        let rx_channel = csp.get_rx_channel();
+        rx_channel.send(incoming(1)).unwrap();
+        rx_channel.send(incoming(1)).unwrap();
+        assert!(csp.csp_read(timeout).is_ok());
+        assert!(csp.csp_read(timeout).is_ok());
+
+        let csp = CSP::with_config(CspConfig::new().dedup(CspDedupMode::CspDedupForward)).unwrap();
+        let rx_channel = csp.get_rx_channel();
+        let broadcast = CSP_BROADCAST_ADDR as u8;
+        for dst in [1, 1, broadcast, broadcast, 9, 9] {
+            rx_channel.send(incoming(dst)).unwrap();
+        }
+        assert_eq!(csp.csp_read(timeout).unwrap().id.dst, 1);
+        assert_eq!(csp.csp_read(timeout).unwrap().id.dst, 1);
+        assert_eq!(csp.csp_read(timeout).unwrap().id.dst, broadcast);
+        assert_eq!(csp.csp_read(timeout).unwrap().id.dst, broadcast);
+        assert_eq!(csp.csp_read(timeout).unwrap().id.dst, 9);
+        assert!(matches!(
+            csp.csp_read(timeout),
+            Err(CspError::CspErrTimedOut)
+        ));
+        assert_eq!(csp.csp_dedup_drops(), 1);
+
+        let csp = CSP::with_config(CspConfig::new().dedup(CspDedupMode::CspDedupAll)).unwrap();
+        let rx_channel = csp.get_rx_channel();
+        rx_channel.send(incoming(1)).unwrap();
+        rx_channel.send(incoming(1)).unwrap();
+        assert!(csp.csp_read(timeout).is_ok());
+        assert!(csp.csp_read(timeout).is_err());
+        assert_eq!(csp.csp_dedup_drops(), 1);
+        assert_eq!(iface.stats.snapshot().drop, 2);
+    }
+
+    #[test]
+    fn split_horizon_test() {
+        let csp = CSP::with_config(CspConfig::new().address(1)).unwrap();
+        let radio = CspIface::new(1, 5, "RADIO".to_string());
+        let mut lan = CspIface::new(1, 5, "LAN".to_string());
+        lan.split_horizon_off = 1;
+        for intf in [radio.clone(), lan.clone()] {
+            csp.add_interface(Box::new(SinkIntf { intf })).unwrap();
+        }
+        csp.csp_rtable_set(9, 5, "RADIO", CSP_NO_VIA_ADDRESS)
+            .unwrap();
+        csp.csp_rtable_set(10, 5, "LAN", CSP_NO_VIA_ADDRESS)
+            .unwrap();
+
+        let timeout = Duration::from_millis(10);
+        let rx_channel = csp.get_rx_channel();
+        let incoming = |iface: &CspIface, dst: u8| CspFIFO {
+            iface: iface.clone(),
+            packet: CspPacket::new().id(CspId::new().dst(dst)).data(vec![1]),
+        };
+        rx_channel.send(incoming(&radio, 9)).unwrap();
+        rx_channel.send(incoming(&radio, 10)).unwrap();
+        rx_channel.send(incoming(&lan, 10)).unwrap();
+        rx_channel.send(incoming(&lan, 1)).unwrap();
+
+        assert!(csp.csp_route_work(timeout).unwrap().is_none());
+        assert!(csp.csp_route_work(timeout).unwrap().is_none());
+        assert!(csp.csp_route_work(timeout).unwrap().is_none());
+        assert_eq!(csp.csp_route_work(timeout).unwrap().unwrap().id.dst, 1);
+
+        assert_eq!(csp.csp_split_horizon_drops(), 1);
+        assert_eq!(radio.stats.snapshot().drop, 1);
+        assert_eq!(radio.stats.snapshot().tx, 0);
+        assert_eq!(lan.stats.snapshot().tx, 2);
+    }
+
+    #[test]
+    fn promisc_test() {
+        let csp = CSP::new();
+        csp.add_interface(Box::new(SinkIntf {
+            intf: CspIface::new(1, 5, "SINK".to_string()),
+        }))
+        .unwrap();
+        let rx_channel = csp.get_rx_channel();
+        let timeout = Duration::from_millis(10);
+        let incoming = |sport: u8| CspFIFO {
+            iface: CspIface::new(1, 5, "SINK".to_string()),
+            packet: CspPacket::new().id(CspId::new().dst(1).sport(sport)),
+        };
+
+        assert!(matches!(
+            csp.csp_promisc_read(timeout),
+            Err(CspError::CspErrNotSup)
+        ));
+        rx_channel.send(incoming(1)).unwrap();
+        csp.csp_read(timeout).unwrap();
+
+        csp.csp_promisc_enable(2).unwrap();
+        assert!(matches!(
+            csp.csp_promisc_enable(8),
+            Err(CspError::CspErrAlready)
+        ));
+        assert!(matches!(
+            csp.csp_promisc_read(timeout),
+            Err(CspError::CspErrTimedOut)
+        ));
+
+        let mut conn = CspConnection::new();
+        conn.state = ConnState::ConnOpen;
+        conn.idout = CspId::new().dst(9).dport(10);
+        assert!(csp
+            .csp_send(&mut conn, &mut CspPacket::new().data(vec![0; 256]))
+            .is_err());
+        csp.csp_send(&mut conn, &mut CspPacket::new().data(vec![1, 2]))
+            .unwrap();
+        let stats = csp
+            .iflist_get_by_name("SINK")
+            .unwrap()
+            .iface()
+            .stats
+            .snapshot();
+        assert_eq!((stats.tx, stats.tx_error), (1, 1));
+        assert_eq!(stats.txbytes, 2);
+
+        for sport in 2..5 {
+            rx_channel.send(incoming(sport)).unwrap();
+            csp.csp_read(timeout).unwrap();
+        }
+
+        assert_eq!(csp.csp_promisc_read(timeout).unwrap().data, vec![1, 2]);
+        assert_eq!(csp.csp_promisc_read(timeout).unwrap().id.sport, 2);
+        assert!(csp.csp_promisc_read(timeout).is_err());
+
+        csp.csp_promisc_disable();
+        assert!(csp.csp_promisc_read(timeout).is_err());
+    }
+
     #[test]
     #[ignore]
     fn send_test() {
-        if std::env::args().len() > 1 {
-            if std::env::args().nth(1).unwrap() == "nouart" {
-                println!("No UART");
-                ()
-            }
+        if std::env::args().len() > 1 && std::env::args().nth(1).unwrap() == "nouart" {
+            println!("No UART");
         }
 
         let test_csp_id = CspId {
@@ -110,12 +1500,13 @@ mod tests {
 
         let mut test_conn = CspConnection::new();
         test_conn.state = ConnState::ConnOpen;
+        test_conn.idout = test_csp_id;
 
-        let mut csp = CSP::new();
+        let csp = CSP::new();
         intf.rx_channel = Some(csp.get_rx_channel());
 
-        let kiss_intf = KissIntfData::new(intf, uart_config, "/dev/pts/4".to_string());
-        csp.add_interface(Box::new(kiss_intf));
+        let kiss_intf = KissIntfData::new(intf, uart_config, "/dev/pts/4".to_string()).unwrap();
+        csp.add_interface(Box::new(kiss_intf)).unwrap();
 
         let mut test_pkt = CspPacket::new()
             .data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9])
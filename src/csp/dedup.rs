@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: MIT
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::csp::types::*;
+
+/// Which incoming packets are checked for duplicates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CspDedupMode {
+    CspDedupOff,
+    /// Only packets addressed neither to this node nor to broadcast, i.e. the ones to forward
+    CspDedupForward,
+    CspDedupAll,
+}
+
+/**
+ * Window of the hashes of the last packets received, a packet whose hash is in the window is a
+ * duplicate. Hashes expire after the window time
+ */
+pub struct CspDedup {
+    count: usize,
+    window: Duration,
+    hashes: VecDeque<(u32, Instant)>,
+}
+
+/// Hash of header and payload
+fn csp_dedup_hash(packet: &CspPacket) -> u32 {
+    let mut digest = CSPCRC32.digest();
+    digest.update(&packet.id.to_bytes());
+    digest.update(&packet.data);
+    digest.finalize()
+}
+
+impl CspDedup {
+    pub fn new(count: usize, window: Duration) -> Self {
+        Self {
+            count,
+            window,
+            hashes: VecDeque::with_capacity(count),
+        }
+    }
+
+    /// Returns true if packet was seen within the window, otherwise it is added to it
+    pub fn is_duplicate(&mut self, packet: &CspPacket, now: Instant) -> bool {
+        while let Some((_, t)) = self.hashes.front() {
+            if now.duration_since(*t) < self.window {
+                break;
+            }
+            self.hashes.pop_front();
+        }
+
+        let hash = csp_dedup_hash(packet);
+        if self.hashes.iter().any(|(h, _)| *h == hash) {
+            return true;
+        }
+
+        if self.hashes.len() >= self.count {
+            self.hashes.pop_front();
+        }
+        self.hashes.push_back((hash, now));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_test() {
+        let mut dedup = CspDedup::new(2, Duration::from_millis(1000));
+        let now = Instant::now();
+        let packet = |dport: u8, data: u8| {
+            CspPacket::new()
+                .id(CspId::new().dst(1).dport(dport))
+                .data(vec![data])
+        };
+
+        assert!(!dedup.is_duplicate(&packet(10, 1), now));
+        assert!(dedup.is_duplicate(&packet(10, 1), now));
+        assert!(!dedup.is_duplicate(&packet(11, 1), now));
+        assert!(!dedup.is_duplicate(&packet(10, 2), now));
+
+        // Window full, the oldest hash was replaced
+        assert!(!dedup.is_duplicate(&packet(10, 1), now));
+
+        // Expired
+        let later = now + Duration::from_millis(1000);
+        assert!(!dedup.is_duplicate(&packet(10, 2), later));
+        assert!(dedup.is_duplicate(&packet(10, 2), later));
+    }
+}
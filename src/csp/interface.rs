@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: MIT
 
-use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
 use crate::csp::types::*;
 
@@ -14,6 +15,32 @@ pub struct CspIface {
     pub name: String,
     pub mtu: u16,
     pub split_horizon_off: u8,
+    pub stats: Arc<CspIfaceStats>,
+    pub rx_channel: Option<std::sync::mpsc::SyncSender<CspFIFO>>,
+}
+
+/**
+ * Interface counters, shared by every clone of the CspIface they belong to
+ */
+#[derive(Default)]
+pub struct CspIfaceStats {
+    pub tx: AtomicU32,
+    pub rx: AtomicU32,
+    pub tx_error: AtomicU32,
+    pub rx_error: AtomicU32,
+    pub drop: AtomicU32,
+    pub autherr: AtomicU32,
+    pub frame: AtomicU32,
+    pub txbytes: AtomicU32,
+    pub rxbytes: AtomicU32,
+    pub irq: AtomicU32,
+    /// Packets dropped for exceeding the MTU, not reported by CMP
+    pub oversize: AtomicU32,
+}
+
+/// Copy of the interface counters at a given time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CspIfaceStatsSnapshot {
     pub tx: u32,
     pub rx: u32,
     pub tx_error: u32,
@@ -24,13 +51,33 @@ pub struct CspIface {
     pub txbytes: u32,
     pub rxbytes: u32,
     pub irq: u32,
-    pub rx_channel: Option<std::sync::mpsc::SyncSender<CspFIFO>>,
+    pub oversize: u32,
 }
 
-pub trait NextHop {
-    fn next_hop(&self, via: u16, packet: &mut CspPacket, from_me: bool) -> Result<(), io::Error>;
+pub trait NextHop: Send + Sync {
+    fn next_hop(&self, via: u16, packet: &mut CspPacket, from_me: bool) -> Result<(), CspError>;
+    fn iface(&self) -> &CspIface;
+
+    fn name(&self) -> &str {
+        &self.iface().name
+    }
+
+    /// Stops the interface, joining any thread it runs. Called by CSP::shutdown
+    fn close(&self) {}
+
+    /// Future waiting until frames packets can be sent without failing with CspErrNoBufs, None
+    /// for interfaces with no TX queue to wait on
+    #[cfg(feature = "tokio")]
+    fn tx_ready(&self, _frames: usize) -> Option<CspTxReady<'_>> {
+        None
+    }
 }
 
+/// Future returned by NextHop::tx_ready
+#[cfg(feature = "tokio")]
+pub type CspTxReady<'a> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), CspError>> + Send + 'a>>;
+
 impl CspIface {
     pub fn new(addr: u16, netmask: u16, name: String) -> CspIface {
         Self {
@@ -39,17 +86,60 @@ impl CspIface {
             name,
             mtu: 255,
             split_horizon_off: 0,
-            tx: 0,
-            rx: 0,
-            tx_error: 0,
-            rx_error: 0,
-            drop: 0,
-            autherr: 0,
-            frame: 0,
-            txbytes: 0,
-            rxbytes: 0,
-            irq: 0,
+            stats: Arc::new(CspIfaceStats::default()),
             rx_channel: None,
         }
     }
 }
+
+impl CspIfaceStats {
+    /// Adds one to counter
+    pub fn inc(counter: &AtomicU32) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU32, value: u32) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CspIfaceStatsSnapshot {
+        CspIfaceStatsSnapshot {
+            tx: self.tx.load(Ordering::Relaxed),
+            rx: self.rx.load(Ordering::Relaxed),
+            tx_error: self.tx_error.load(Ordering::Relaxed),
+            rx_error: self.rx_error.load(Ordering::Relaxed),
+            drop: self.drop.load(Ordering::Relaxed),
+            autherr: self.autherr.load(Ordering::Relaxed),
+            frame: self.frame.load(Ordering::Relaxed),
+            txbytes: self.txbytes.load(Ordering::Relaxed),
+            rxbytes: self.rxbytes.load(Ordering::Relaxed),
+            irq: self.irq.load(Ordering::Relaxed),
+            oversize: self.oversize.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iface_stats_shared_test() {
+        let intf = CspIface::new(1, 5, "KISS".to_string());
+        let rx_thread_copy = intf.clone();
+
+        std::thread::spawn(move || {
+            CspIfaceStats::inc(&rx_thread_copy.stats.rx);
+            CspIfaceStats::add(&rx_thread_copy.stats.rxbytes, 100);
+        })
+        .join()
+        .unwrap();
+        CspIfaceStats::inc(&intf.stats.tx_error);
+
+        let stats = intf.stats.snapshot();
+        assert_eq!(stats.rx, 1);
+        assert_eq!(stats.rxbytes, 100);
+        assert_eq!(stats.tx_error, 1);
+        assert_eq!(stats.tx, 0);
+    }
+}
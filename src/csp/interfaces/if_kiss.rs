@@ -1,20 +1,46 @@
 // SPDX-License-Identifier: MIT
 
-use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
 use std::time::Duration;
 
 use byteorder::ByteOrder;
-use bytes::Bytes;
 use serialport::{DataBits, SerialPort, StopBits};
 
+use crate::csp::csp::{csp_lock, csp_read_lock, csp_write_lock};
 use crate::csp::interface::*;
+use crate::csp::qfifo::csp_qfifo_write;
 use crate::csp::types::*;
 
-const FEND: u8 = 0xC0;
+pub(crate) const FEND: u8 = 0xC0;
 const FESC: u8 = 0xDB;
 const TFEND: u8 = 0xDC;
 const TFESC: u8 = 0xDD;
-const TNC_DATA: u8 = 0x00;
+
+/// Number of TNC ports, the port is the high nibble of the command byte
+pub const KISS_TNC_PORTS: usize = 16;
+
+/// How often the RX thread checks whether it has to stop
+const KISS_RX_POLL: Duration = Duration::from_millis(100);
+
+/// KISS command, low nibble of the byte following FEND
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CspKissCmd {
+    KissCmdData = 0x00,
+    KissCmdTxDelay = 0x01,     // Keyup delay, in 10 ms units
+    KissCmdP = 0x02,           // Persistence for CSMA, p = (value + 1) / 256
+    KissCmdSlotTime = 0x03,    // Slot interval, in 10 ms units
+    KissCmdTxTail = 0x04,      // Time to hold up TX after the frame, in 10 ms units
+    KissCmdFullDuplex = 0x05,  // 0 half duplex, otherwise full duplex
+    KissCmdSetHardware = 0x06, // TNC specific
+}
+
+/// Logical interfaces sharing a KISS line, indexed by TNC port. Port 0 is the line's own
+/// interface
+pub type KissTncPorts = Arc<RwLock<[Option<CspIface>; KISS_TNC_PORTS]>>;
+
+type KissPort = Arc<Mutex<Option<Box<dyn SerialPort>>>>;
 
 #[derive(Clone)]
 pub enum CspKissMode {
@@ -26,14 +52,33 @@ pub enum CspKissMode {
 
 pub struct KissIntfData {
     pub intf: CspIface,
-    pub port: Option<Box<dyn SerialPort>>,
+    port: KissPort,
+    tnc_ports: KissTncPorts,
+    running: Arc<AtomicBool>,
+    rx_thread: Mutex<Option<JoinHandle<()>>>,
 }
 
-struct KissIntfDataRx {
+/**
+ * Logical CSP interface on another TNC port of a KISS line, created with
+ * KissIntfData::tnc_port. It shares the serial port and RX thread of the line
+ */
+pub struct KissTncIntf {
+    pub intf: CspIface,
+    tnc_port: u8,
+    port: KissPort,
+    tnc_ports: KissTncPorts,
+}
+
+pub(crate) struct KissIntfDataRx {
     pub rx_mode: CspKissMode,
+    /// Largest payload accepted, the MTU of the interface. Longer frames are skipped
     pub max_rx_length: usize,
     pub rx_length: u32,
     pub rx_first: bool,
+    /// Command byte of the last frame, TNC port and command
+    pub rx_cmd: u8,
+    /// Bytes read from the line and not decoded yet
+    rx_buf: Vec<u8>,
 }
 
 pub struct PortConfig {
@@ -43,24 +88,89 @@ pub struct PortConfig {
 }
 
 impl KissIntfData {
-    pub fn new(intf: CspIface, config: PortConfig, ifname: String) -> Self {
+    /// Opens the serial port ifname and starts its RX thread
+    pub fn new(intf: CspIface, config: PortConfig, ifname: String) -> Result<Self, CspError> {
         let builder = serialport::new(&ifname, config.baud_rate)
             .stop_bits(config.stopbits)
             .data_bits(config.data_bits)
             .timeout(Duration::from_millis(10000));
-        let p = builder.open().unwrap();
-        let q = p.try_clone().unwrap();
-
-        let newval = KissIntfData {
-            intf: intf.clone(),
-            port: Some(p),
-        };
+        let p = builder.open().map_err(|e| {
+            error!("Cannot open {} for KISS interface: {}", ifname, e);
+            CspError::from(e)
+        })?;
 
         info!("Creating KISS ({}) interface", ifname);
 
-        std::thread::spawn(move || usart_rx_func(q, &intf.clone()));
+        Self::from_port(intf, p)
+    }
+
+    /// Uses an already opened serial port and starts its RX thread
+    pub fn from_port(intf: CspIface, port: Box<dyn SerialPort>) -> Result<Self, CspError> {
+        let mut q = port.try_clone()?;
+        q.set_timeout(KISS_RX_POLL)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let rx_running = running.clone();
+        let tnc_ports: KissTncPorts = Arc::new(RwLock::new(Default::default()));
+        csp_write_lock(&tnc_ports)[0] = Some(intf.clone());
+        let rx_ports = tnc_ports.clone();
+        let rx_thread = std::thread::Builder::new()
+            .name(format!("kiss-rx-{}", intf.name))
+            .spawn(move || usart_rx_func(q, &rx_ports, &rx_running))?;
+
+        Ok(KissIntfData {
+            intf,
+            port: Arc::new(Mutex::new(Some(port))),
+            tnc_ports,
+            running,
+            rx_thread: Mutex::new(Some(rx_thread)),
+        })
+    }
+
+    /// Creates the interface intf on TNC port tnc_port (1 to 15) of this line
+    pub fn tnc_port(&self, intf: CspIface, tnc_port: u8) -> Result<KissTncIntf, CspError> {
+        if tnc_port == 0 || tnc_port as usize >= KISS_TNC_PORTS {
+            return Err(CspError::inval("KISS TNC port out of range"));
+        }
+
+        let mut tnc_ports = csp_write_lock(&self.tnc_ports);
+        let slot = &mut tnc_ports[tnc_port as usize];
+        if slot.is_some() {
+            warn!(
+                "KISS TNC port {} of {} already used",
+                tnc_port, self.intf.name
+            );
+            return Err(CspError::CspErrUsed);
+        }
+        *slot = Some(intf.clone());
+
+        info!(
+            "Creating KISS ({}) interface on TNC port {} of {}",
+            intf.name, tnc_port, self.intf.name
+        );
+        Ok(KissTncIntf {
+            intf,
+            tnc_port,
+            port: self.port.clone(),
+            tnc_ports: self.tnc_ports.clone(),
+        })
+    }
+
+    /// Sends a command to the TNC, e.g. TXDELAY, on TNC port 0
+    pub fn kiss_command(&self, cmd: CspKissCmd, data: &[u8]) -> Result<(), CspError> {
+        kiss_write(&self.port, &self.intf, &kiss_frame(0, cmd, data))
+    }
 
-        newval
+    /// Stops and joins the RX thread and closes the port, TX fails afterwards
+    pub fn close(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(t) = csp_lock(&self.rx_thread).take() {
+            if t.join().is_err() {
+                warn!("KISS interface {} RX thread panicked", self.intf.name);
+            }
+            info!("KISS interface {} closed", self.intf.name);
+        }
+        *csp_lock(&self.port) = None;
     }
 
     pub fn csp_kiss_tx(
@@ -68,62 +178,151 @@ impl KissIntfData {
         _via: u16,
         packet: &mut crate::csp::types::CspPacket,
         _from_me: bool,
-    ) -> Result<(), io::Error> {
+    ) -> Result<(), CspError> {
         debug!("Kiss TX {} {}", self.intf.name, packet.data.len());
 
-        packet.csp_crc32_append();
-
-        let flags = packet.id.flags as u8;
-        let cspid_low = (packet.id.sport & 0x3F) | (packet.id.dport & 0x3) << 6;
-        let cspid_med = ((packet.id.dport & 0x3C) >> 2) | (packet.id.dst & 0x1F) << 4;
-        let cspid_high =
-            (packet.id.pri << 6) | (packet.id.src & 0x3F) << 1 | (packet.id.dst & 0x10) >> 4;
-
-        packet.data.insert(1, cspid_high);
-        packet.data.insert(2, cspid_med);
-        packet.data.insert(3, cspid_low);
-        packet.data.insert(4, flags);
-        packet.data.insert(5, 0x00); //don't now why this extra byte, maybe padding?
-
-        let kiss_buf = kiss_process_tx(&packet.data, packet.data.len());
-        let kiss_len = kiss_buf.len();
-        let mem_buff = Bytes::from(kiss_buf);
-
-        match &self.port {
-            //TODO: better error management
-            None => {
-                error!("Port not initialized for KISS interface");
-                panic!("Port not initialized for KISS interface");
-            }
-            Some(p) => {
-                let mut cl = p.try_clone()?;
-                cl.write_all(mem_buff.split_at(kiss_len).0)?;
-            }
-        };
-        Ok(())
+        kiss_write(&self.port, &self.intf, &kiss_encode(packet))
+    }
+}
+
+impl KissTncIntf {
+    /// Sends a command to the TNC on the port of this interface
+    pub fn kiss_command(&self, cmd: CspKissCmd, data: &[u8]) -> Result<(), CspError> {
+        kiss_write(
+            &self.port,
+            &self.intf,
+            &kiss_frame(self.tnc_port, cmd, data),
+        )
+    }
+}
+
+impl NextHop for KissTncIntf {
+    fn next_hop(&self, _via: u16, packet: &mut CspPacket, _from_me: bool) -> Result<(), CspError> {
+        debug!(
+            "Kiss TX {} (TNC port {}) {}",
+            self.intf.name,
+            self.tnc_port,
+            packet.data.len()
+        );
+
+        kiss_write(
+            &self.port,
+            &self.intf,
+            &kiss_encode_port(packet, self.tnc_port),
+        )
+    }
+
+    fn iface(&self) -> &CspIface {
+        &self.intf
+    }
+}
+
+impl Drop for KissTncIntf {
+    fn drop(&mut self) {
+        csp_write_lock(&self.tnc_ports)[self.tnc_port as usize] = None;
+    }
+}
+
+/// Writes an encoded frame to the line
+fn kiss_write(port: &KissPort, intf: &CspIface, frame: &[u8]) -> Result<(), CspError> {
+    match &*csp_lock(port) {
+        None => {
+            warn!("Port not initialized for KISS interface {}", intf.name);
+            Err(CspError::CspErrTx)
+        }
+        Some(p) => {
+            let mut cl = p.try_clone()?;
+            cl.write_all(frame)?;
+            Ok(())
+        }
     }
 }
 
 impl crate::csp::interface::NextHop for KissIntfData {
-    fn next_hop(&self, _via: u16, packet: &mut CspPacket, _from_me: bool) -> Result<(), io::Error> {
+    fn next_hop(&self, _via: u16, packet: &mut CspPacket, _from_me: bool) -> Result<(), CspError> {
         self.csp_kiss_tx(_via, packet, _from_me)
     }
+
+    fn iface(&self) -> &CspIface {
+        &self.intf
+    }
+
+    fn close(&self) {
+        KissIntfData::close(self)
+    }
+}
+
+impl Drop for KissIntfData {
+    fn drop(&mut self) {
+        self.close();
+    }
 }
 
-pub fn usart_rx_func(port: Box<dyn SerialPort>, intf: &CspIface) {
+/// RX loop of a KISS line, runs until running is cleared or the port fails. Packets go to the
+/// interface of their TNC port
+pub fn usart_rx_func(
+    mut port: Box<dyn SerialPort>,
+    tnc_ports: &KissTncPorts,
+    running: &AtomicBool,
+) {
     let mut rx_intf = KissIntfDataRx::new();
-    let cl = port.try_clone().unwrap();
+    while running.load(Ordering::Relaxed) {
+        match rx_intf.csp_kiss_rx(port.as_mut(), tnc_ports) {
+            Ok(()) | Err(CspError::CspErrTimedOut) => {}
+            Err(e) => {
+                if let Some(intf) = &csp_read_lock(tnc_ports)[0] {
+                    error!("KISS interface {} RX stopped: {}", intf.name, e);
+                    CspIfaceStats::inc(&intf.stats.rx_error);
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Builds the KISS frame of a packet: header, data and CRC32, escaped
+pub fn kiss_encode(packet: &CspPacket) -> Vec<u8> {
+    kiss_encode_port(packet, 0)
+}
+
+/// Same as kiss_encode, for TNC port tnc_port
+pub fn kiss_encode_port(packet: &CspPacket, tnc_port: u8) -> Vec<u8> {
+    let mut frame = set_packet_id(&packet.id).to_vec();
+    frame.extend_from_slice(&packet.data);
+    frame.extend_from_slice(&csp_crc32_calc(&packet.data).to_be_bytes());
+
+    kiss_frame(tnc_port, CspKissCmd::KissCmdData, &frame)
+}
+
+/// Finds the first complete frame in buf, returning where it starts and the index of its
+/// closing FEND. Empty frames between two FEND are skipped
+pub fn kiss_next_frame(buf: &[u8]) -> Option<(usize, usize)> {
+    let mut start = buf.iter().position(|b| *b == FEND)?;
     loop {
-        let _res = rx_intf.csp_kiss_rx(&cl, intf.clone());
-        println!("RX Loop");
+        let end = start + 1 + buf[start + 1..].iter().position(|b| *b == FEND)?;
+        if end > start + 1 {
+            return Some((start, end));
+        }
+        start = end;
     }
 }
 
+/// TNC port of a frame returned by kiss_next_frame, the high nibble of the command byte
+/// following the opening FEND
+pub fn kiss_tnc_port(frame: &[u8]) -> u8 {
+    frame[1] >> 4
+}
+
 pub fn kiss_process_tx(data: &[u8], len: usize) -> Vec<u8> {
+    kiss_frame(0, CspKissCmd::KissCmdData, &data[..len])
+}
+
+/// Escapes data in a frame with the command byte of cmd on TNC port tnc_port
+pub fn kiss_frame(tnc_port: u8, cmd: CspKissCmd, data: &[u8]) -> Vec<u8> {
     // start
-    let mut res = vec![FEND, TNC_DATA];
+    let mut res = vec![FEND, (tnc_port << 4) | cmd as u8];
 
-    for item in data.iter().take(len).skip(1) {
+    for item in data {
         if *item == FEND {
             res.push(FESC);
             res.push(TFEND);
@@ -149,48 +348,95 @@ impl KissIntfDataRx {
             max_rx_length: 256,
             rx_first: true,
             rx_length: 0,
+            rx_cmd: 0,
+            rx_buf: Vec::new(),
             rx_mode: CspKissMode::KissModeNotStarted,
         }
     }
 
     fn csp_kiss_rx(
         self: &mut KissIntfDataRx,
-        port: &Box<dyn SerialPort>,
-        intf: CspIface,
-    ) -> Result<(), io::Error> {
+        port: &mut dyn SerialPort,
+        tnc_ports: &KissTncPorts,
+    ) -> Result<(), CspError> {
         let mut serial_buf: Vec<u8> = vec![0; self.max_rx_length];
-        let mut cl = port.try_clone()?;
-
-        let r = cl.read(serial_buf.as_mut_slice());
-        match r {
-            Ok(t) => {
-                let packet = kiss_process_rx(serial_buf, t, self);
-                //if packet.is_ok() {
-                if let Ok(p) = packet {
-                    let fifo_pkt = CspFIFO {
-                        iface: intf.clone(),
-                        packet: p,
-                    };
-
-                    if intf.rx_channel.is_some() {
-                        let _res = intf.rx_channel.unwrap().send(fifo_pkt);
-                    } else {
-                        error!("No RX fifo");
+        let t = port.read(serial_buf.as_mut_slice())?;
+        self.rx_buf.extend_from_slice(&serial_buf[..t]);
+
+        let tnc_ports = csp_read_lock(tnc_ports);
+        while let Some((start, end)) = kiss_next_frame(&self.rx_buf) {
+            // The closing FEND may open the next frame
+            let frame = self.rx_buf[start..=end].to_vec();
+            self.rx_buf.drain(..end);
+
+            let tnc_port = kiss_tnc_port(&frame) as usize;
+            let intf = match &tnc_ports[tnc_port] {
+                Some(intf) => intf,
+                None => {
+                    debug!("No interface on KISS TNC port {}", tnc_port);
+                    if let Some(intf) = &tnc_ports[0] {
+                        CspIfaceStats::inc(&intf.stats.drop);
                     }
+                    continue;
                 }
+            };
+
+            self.rx_mode = CspKissMode::KissModeNotStarted;
+            self.max_rx_length = intf.mtu as usize;
+            let len = frame.len();
+            kiss_rx_deliver(kiss_process_rx(frame, len, self), intf);
+        }
 
-                Ok(())
+        // Keep the unfinished frame only, escaping can double its length
+        let mtu = tnc_ports
+            .iter()
+            .flatten()
+            .map(|intf| intf.mtu as usize)
+            .max()
+            .unwrap_or(self.max_rx_length);
+        match self.rx_buf.iter().rposition(|b| *b == FEND) {
+            Some(start) if self.rx_buf.len() - start > kiss_max_frame(mtu) => {
+                warn!("KISS frame too long, discarding");
+                if let Some(intf) = &tnc_ports[0] {
+                    CspIfaceStats::inc(&intf.stats.oversize);
+                }
+                self.rx_buf.clear();
             }
-            Err(e) => Err(e),
+            Some(start) => {
+                self.rx_buf.drain(..start);
+            }
+            None => self.rx_buf.clear(),
         }
+
+        Ok(())
+    }
+}
+
+/// Largest escaped frame carrying mtu bytes of payload, every byte of header, payload and CRC
+/// may be escaped
+pub(crate) fn kiss_max_frame(mtu: usize) -> usize {
+    2 * (mtu + 8) + 3
+}
+
+/// Queues a decoded packet to intf or counts why the frame was dropped
+pub(crate) fn kiss_rx_deliver(res: Result<CspPacket, CspError>, intf: &CspIface) {
+    match res {
+        Ok(p) => csp_qfifo_write(p, intf),
+        // Command frame
+        Err(CspError::CspErrNotSup) => {}
+        Err(CspError::CspErrCrc32) => CspIfaceStats::inc(&intf.stats.rx_error),
+        Err(CspError::CspErrNoMem) => CspIfaceStats::inc(&intf.stats.oversize),
+        Err(_) => CspIfaceStats::inc(&intf.stats.frame),
     }
 }
 
-fn kiss_process_rx(
+/// Decodes a KISS frame. Command frames fail with CspErrNotSup and frames with more than
+/// max_rx_length bytes of payload with CspErrNoMem
+pub(crate) fn kiss_process_rx(
     data: Vec<u8>,
     len: usize,
     intf: &mut KissIntfDataRx,
-) -> Result<crate::csp::types::CspPacket, io::Error> {
+) -> Result<crate::csp::types::CspPacket, CspError> {
     let mut n = 0;
     let mut packet = crate::csp::types::CspPacket::new();
 
@@ -203,9 +449,6 @@ fn kiss_process_rx(
                     break;
                 }
 
-                if packet.data.len() > intf.max_rx_length {
-                    intf.rx_mode = CspKissMode::KissModeSkipFrame;
-                }
                 intf.rx_first = true;
                 intf.rx_length = 0;
                 intf.rx_mode = CspKissMode::KissModeStarted;
@@ -221,12 +464,9 @@ fn kiss_process_rx(
 
                     let len = packet.data.len();
 
-                    if len < 5 {
+                    if len < 8 {
                         warn!("Invalid pkt length");
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Invalid length",
-                        ));
+                        return Err(CspError::inval("KISS frame too short"));
                     }
 
                     debug!("Data: {:x?}", packet.data);
@@ -251,9 +491,10 @@ fn kiss_process_rx(
 
                     if pkt_crc != calc_crc {
                         warn!("Error CRC");
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, "CRC Error"));
+                        return Err(CspError::CspErrCrc32);
                     } else {
                         debug!("CRC OK!");
+                        packet.data.truncate(len - 4);
                         info!("Accepted packet {:?}", packet.id);
                         return Ok(packet);
                     }
@@ -261,9 +502,22 @@ fn kiss_process_rx(
 
                 if intf.rx_first {
                     intf.rx_first = false;
+                    intf.rx_cmd = inputbyte;
+                    if inputbyte & 0x0F != CspKissCmd::KissCmdData as u8 {
+                        debug!("Skipping KISS command frame {:#04x}", inputbyte);
+                        intf.rx_mode = CspKissMode::KissModeSkipFrame;
+                        return Err(CspError::CspErrNotSup);
+                    }
                     continue;
                 }
 
+                // Header and CRC come on top of the payload
+                if packet.data.len() >= intf.max_rx_length + 8 {
+                    warn!("KISS frame too long, skipping");
+                    intf.rx_mode = CspKissMode::KissModeSkipFrame;
+                    return Err(CspError::CspErrNoMem);
+                }
+
                 packet.data.push(inputbyte);
             }
             CspKissMode::KissModeEscaped => {
@@ -287,20 +541,12 @@ fn kiss_process_rx(
     Ok(packet)
 }
 
-// CSP 1.0
-// | Byte0 | Byte 1 | Byte 2 | Byte 3 |
-// | 2 PRIO | 5 SOURCE | 5 DESTINATION | 6 DESTINATION PORT | 6 SOURCE PORT | 8 FLAGS |
 fn get_packet_id(byte0: u8, byte1: u8, byte2: u8, byte3: u8) -> CspId {
-    let mut ret_val = CspId::new();
-
-    ret_val.sport = byte2 & 0x3F;
-    ret_val.dport = byte1 & 0x0F | (byte2 & 0xC0) >> 6;
-    ret_val.dst = (byte1 >> 4) | (byte0 & 0x01) << 4;
-    ret_val.src = (byte0 >> 1) & 0x1F;
-    ret_val.pri = (byte0 >> 6) & 0x03;
-    ret_val.flags = byte3;
+    CspId::from_bytes(&[byte0, byte1, byte2, byte3])
+}
 
-    ret_val
+fn set_packet_id(id: &CspId) -> [u8; 4] {
+    id.to_bytes()
 }
 
 #[cfg(test)]
@@ -311,13 +557,6 @@ mod tests {
     #[test]
     #[ignore]
     pub fn uart() {
-        if std::env::args().len() > 1 {
-            if std::env::args().nth(1).unwrap() == "nouart" {
-                println!("No UART");
-                ()
-            }
-        }
-
         let port_name = "/dev/pts/0".to_string();
         let builder = serialport::new(port_name, 115200)
             .stop_bits(StopBits::One)
@@ -326,18 +565,12 @@ mod tests {
         let mut port = builder.open().unwrap();
 
         let string = "hello world\n".to_string();
-        port.write(string.as_bytes()).unwrap();
+        port.write_all(string.as_bytes()).unwrap();
     }
 
     #[test]
     #[ignore]
     fn csp_nexthop_test() {
-        if std::env::args().len() > 1 {
-            if std::env::args().nth(1).unwrap() == "nouart" {
-                println!("No UART");
-                ()
-            }
-        }
         let my_csp_id = CspId {
             pri: 2,
             flags: 1,
@@ -363,7 +596,7 @@ mod tests {
 
         intf.rx_channel = Some(csp.get_rx_channel());
 
-        let mut kiss_intf = KissIntfData::new(intf, uart_config, "/dev/pts/1".to_string());
+        let mut kiss_intf = KissIntfData::new(intf, uart_config, "/dev/pts/1".to_string()).unwrap();
 
         let result = csp_send_direct_iface(&my_csp_id, &mut pkt, &mut kiss_intf, 0, false);
         assert!(result.is_ok());
@@ -377,13 +610,6 @@ mod tests {
     fn csp_uart_rx_test() {
         pretty_env_logger::init();
 
-        if std::env::args().len() > 1 {
-            if std::env::args().nth(1).unwrap() == "nouart" {
-                println!("No UART");
-                ()
-            }
-        }
-
         let uart_config = PortConfig {
             baud_rate: 115200,
             data_bits: DataBits::Eight,
@@ -392,16 +618,142 @@ mod tests {
 
         let mut intf = CspIface::new(5, 5, "KISS".to_string());
 
-        let mut csp = CSP::new();
+        let csp = CSP::new();
         intf.rx_channel = Some(csp.get_rx_channel());
 
-        let kiss_intf = KissIntfData::new(intf, uart_config, "/dev/pts/5".to_string());
+        let kiss_intf = KissIntfData::new(intf, uart_config, "/dev/pts/5".to_string()).unwrap();
 
-        csp.add_interface(Box::new(kiss_intf));
+        csp.add_interface(Box::new(kiss_intf)).unwrap();
 
         let pkt = csp.csp_read(Duration::from_millis(10000)).unwrap();
-        let data = pkt.data;
-        println!("RX packet: {:02X?}", data);
+        debug!("RX packet: {:02X?}", pkt.data);
+    }
+
+    #[test]
+    fn csp_kiss_missing_port_test() {
+        let uart_config = PortConfig {
+            baud_rate: 115200,
+            data_bits: DataBits::Eight,
+            stopbits: StopBits::One,
+        };
+        let intf = CspIface::new(5, 5, "KISS".to_string());
+        assert!(
+            KissIntfData::new(intf.clone(), uart_config, "/dev/nonexistent".to_string()).is_err()
+        );
+
+        let csp = CSP::new();
+        csp.add_interface(Box::new(KissIntfData {
+            intf,
+            port: Arc::new(Mutex::new(None)),
+            tnc_ports: Arc::new(RwLock::new(Default::default())),
+            running: Arc::new(AtomicBool::new(false)),
+            rx_thread: Mutex::new(None),
+        }))
+        .unwrap();
+        let mut conn = CspConnection::new();
+        conn.state = ConnState::ConnOpen;
+        conn.idout = CspId::new().dst(2);
+        let res = csp.csp_send(&mut conn, &mut CspPacket::new().data(vec![1, 2, 3]));
+        assert!(matches!(res, Err(CspError::CspErrTx)));
+
+        let stats = csp
+            .iflist_get_by_name("KISS")
+            .unwrap()
+            .iface()
+            .stats
+            .snapshot();
+        assert_eq!(stats.tx_error, 1);
+        assert_eq!(stats.tx, 0);
+    }
+
+    #[test]
+    fn csp_kiss_pty_shutdown_test() {
+        let (master, slave) = serialport::TTYPort::pair().unwrap();
+
+        let rx_csp = CSP::new();
+        let mut rx_intf = CspIface::new(2, 5, "KISS".to_string());
+        rx_intf.rx_channel = Some(rx_csp.get_rx_channel());
+        rx_csp
+            .add_interface(Box::new(
+                KissIntfData::from_port(rx_intf, Box::new(slave)).unwrap(),
+            ))
+            .unwrap();
+        let tx_kiss =
+            KissIntfData::from_port(CspIface::new(1, 5, "KISS".to_string()), Box::new(master))
+                .unwrap();
+
+        let id = CspId::new().pri(2).src(1).dst(2).dport(10).sport(33);
+        let mut pkt = CspPacket::new().id(id).data(vec![1, FEND, 3]);
+        tx_kiss.next_hop(2, &mut pkt, true).unwrap();
+        let rx = rx_csp.csp_read(Duration::from_millis(1000)).unwrap();
+        assert_eq!(rx.id, id);
+        assert_eq!(rx.data, vec![1, FEND, 3]);
+
+        let start = std::time::Instant::now();
+        rx_csp.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(rx_csp.iflist_iter().count(), 0);
+
+        tx_kiss.close();
+        assert!(matches!(
+            tx_kiss.next_hop(2, &mut pkt, true),
+            Err(CspError::CspErrTx)
+        ));
+    }
+
+    #[test]
+    fn csp_kiss_tnc_port_test() {
+        assert_eq!(
+            kiss_frame(1, CspKissCmd::KissCmdTxDelay, &[30, FEND]),
+            vec![FEND, 0x11, 30, FESC, TFEND, FEND]
+        );
+
+        let (master, slave) = serialport::TTYPort::pair().unwrap();
+        let rx_csp = CSP::new();
+        let mut rx_intf = CspIface::new(2, 5, "KISS".to_string());
+        rx_intf.rx_channel = Some(rx_csp.get_rx_channel());
+        let rx_kiss = KissIntfData::from_port(rx_intf.clone(), Box::new(slave)).unwrap();
+        let mut rx_intf1 = CspIface::new(2, 5, "KISS1".to_string());
+        rx_intf1.rx_channel = Some(rx_csp.get_rx_channel());
+        let rx_kiss1 = rx_kiss.tnc_port(rx_intf1.clone(), 1).unwrap();
+        assert!(rx_kiss.tnc_port(rx_intf1.clone(), 0).is_err());
+        assert!(matches!(
+            rx_kiss.tnc_port(rx_intf1, 1),
+            Err(CspError::CspErrUsed)
+        ));
+
+        let tx_kiss =
+            KissIntfData::from_port(CspIface::new(1, 5, "KISS".to_string()), Box::new(master))
+                .unwrap();
+        let tx_kiss1 = tx_kiss
+            .tnc_port(CspIface::new(1, 5, "KISS1".to_string()), 1)
+            .unwrap();
+
+        let timeout = Duration::from_millis(1000);
+        let id = CspId::new().pri(2).src(1).dst(2).dport(10).sport(33);
+        tx_kiss1
+            .kiss_command(CspKissCmd::KissCmdTxDelay, &[30])
+            .unwrap();
+        tx_kiss1
+            .next_hop(2, &mut CspPacket::new().id(id).data(vec![1]), true)
+            .unwrap();
+        let fifo = rx_csp.csp_read_fifo(timeout).unwrap();
+        assert_eq!(fifo.iface.name, "KISS1");
+        assert_eq!(fifo.packet.data, vec![1]);
+
+        tx_kiss
+            .next_hop(2, &mut CspPacket::new().id(id).data(vec![2]), true)
+            .unwrap();
+        assert_eq!(rx_csp.csp_read_fifo(timeout).unwrap().iface.name, "KISS");
+
+        // Nothing listens on port 1 anymore
+        drop(rx_kiss1);
+        tx_kiss1
+            .next_hop(2, &mut CspPacket::new().id(id).data(vec![3]), true)
+            .unwrap();
+        assert!(rx_csp.csp_read_fifo(Duration::from_millis(200)).is_err());
+        assert_eq!(rx_intf.stats.snapshot().drop, 1);
+        assert_eq!(rx_intf.stats.snapshot().frame, 0);
     }
 
     #[test]
@@ -411,14 +763,12 @@ mod tests {
         let data = vec![0xC0, 0x00, 0xDB, 0xDC, 0xDB, 0xDD];
         let mut kiss_intf_rx = KissIntfDataRx::new();
         let pkt = kiss_process_rx(data, 6, &mut kiss_intf_rx).unwrap();
-        println!("len: {} Data: {:#02x?}", pkt.data.len(), pkt.data);
         assert_eq!(pkt.data, vec![0xC0, 0xDB]);
     }
 
     #[test]
     fn csp_get_packet_id() {
         let a = get_packet_id(0x82, 0x20, 0x5b, 0x00);
-        println!("{:?}", a);
         let cmp = CspId {
             pri: 2,
             src: 1,
@@ -429,4 +779,49 @@ mod tests {
         };
         assert_eq!(a, cmp);
     }
+
+    #[test]
+    fn csp_set_packet_id() {
+        let id = CspId {
+            pri: 2,
+            src: 1,
+            dst: 2,
+            sport: 27,
+            dport: 1,
+            flags: 0,
+        };
+        assert_eq!(set_packet_id(&id), [0x82, 0x20, 0x5b, 0x00]);
+
+        let id = CspId::new()
+            .pri(3)
+            .src(31)
+            .dst(17)
+            .dport(63)
+            .sport(40)
+            .flags(0x0A);
+        let b = set_packet_id(&id);
+        assert_eq!(get_packet_id(b[0], b[1], b[2], b[3]), id);
+    }
+
+    #[test]
+    fn csp_kiss_tx_rx_test() {
+        let id = CspId::new().pri(2).src(1).dst(2).dport(0).sport(33);
+        let payload = vec![0xFF, 0x01, FEND, FESC, 0x10];
+
+        let mut frame = set_packet_id(&id).to_vec();
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&csp_crc32_calc(&payload).to_be_bytes());
+        let kiss_buf = kiss_process_tx(&frame, frame.len());
+
+        let mut kiss_intf_rx = KissIntfDataRx::new();
+        let len = kiss_buf.len();
+        let pkt = kiss_process_rx(kiss_buf, len, &mut kiss_intf_rx).unwrap();
+        assert_eq!(pkt.id, id);
+        assert_eq!(pkt.data, payload);
+
+        kiss_intf_rx.max_rx_length = 4;
+        let kiss_buf = kiss_encode(&CspPacket::new().id(id).data(payload));
+        let len = kiss_buf.len();
+        assert!(kiss_process_rx(kiss_buf, len, &mut kiss_intf_rx).is_err());
+    }
 }
@@ -0,0 +1,238 @@
+// SPDX-License-Identifier: MIT
+
+use std::sync::Mutex;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::csp::csp::csp_lock;
+use crate::csp::interface::*;
+use crate::csp::interfaces::if_kiss::*;
+use crate::csp::types::*;
+
+/// Frames waiting to be written by the driver task
+const KISS_ASYNC_TX_QUEUE: usize = 16;
+
+/**
+ * KISS interface driven by a tokio task over any byte stream (serial, TCP, pipes). No thread
+ * is used, frames to send are queued to the task and received packets go to the stack RX queue.
+ * Only TNC port 0 is served, frames for other ports are dropped
+ */
+pub struct KissAsyncIntf {
+    intf: CspIface,
+    tx: mpsc::Sender<Vec<u8>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl KissAsyncIntf {
+    /// Starts the driver task on io, must be called from within a tokio runtime
+    pub fn spawn<T>(intf: CspIface, io: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(KISS_ASYNC_TX_QUEUE);
+        info!("Creating async KISS ({}) interface", intf.name);
+        let task = tokio::spawn(kiss_async_task(io, intf.clone(), rx));
+
+        Self {
+            intf,
+            tx,
+            task: Mutex::new(Some(task)),
+        }
+    }
+}
+
+impl NextHop for KissAsyncIntf {
+    fn next_hop(&self, _via: u16, packet: &mut CspPacket, _from_me: bool) -> Result<(), CspError> {
+        debug!("Kiss async TX {} {}", self.intf.name, packet.data.len());
+
+        self.tx.try_send(kiss_encode(packet)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => CspError::CspErrNoBufs,
+            mpsc::error::TrySendError::Closed(_) => CspError::CspErrTx,
+        })
+    }
+
+    fn iface(&self) -> &CspIface {
+        &self.intf
+    }
+
+    fn tx_ready(&self, frames: usize) -> Option<CspTxReady<'_>> {
+        let frames = frames.clamp(1, self.tx.max_capacity());
+        Some(Box::pin(async move {
+            // The slots are only waited for, dropping the permits gives them back
+            match self.tx.reserve_many(frames).await {
+                Ok(_) => Ok(()),
+                Err(_) => Err(CspError::CspErrTx),
+            }
+        }))
+    }
+
+    fn close(&self) {
+        if let Some(task) = csp_lock(&self.task).take() {
+            task.abort();
+            info!("Async KISS interface {} closed", self.intf.name);
+        }
+    }
+}
+
+impl Drop for KissAsyncIntf {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+async fn kiss_async_task<T>(io: T, intf: CspIface, mut frames: mpsc::Receiver<Vec<u8>>)
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let mut rx_buf: Vec<u8> = Vec::new();
+    let mut read_buf = [0u8; 256];
+
+    loop {
+        tokio::select! {
+            r = reader.read(&mut read_buf) => match r {
+                Ok(0) => {
+                    info!("Async KISS interface {} reached end of stream", intf.name);
+                    break;
+                }
+                Ok(n) => {
+                    rx_buf.extend_from_slice(&read_buf[..n]);
+                    kiss_async_rx(&mut rx_buf, &intf);
+                }
+                Err(e) => {
+                    error!("Async KISS interface {} RX stopped: {}", intf.name, e);
+                    CspIfaceStats::inc(&intf.stats.rx_error);
+                    break;
+                }
+            },
+            frame = frames.recv() => match frame {
+                Some(frame) => {
+                    if let Err(e) = writer.write_all(&frame).await {
+                        warn!("Async KISS interface {} TX failed: {}", intf.name, e);
+                        CspIfaceStats::inc(&intf.stats.tx_error);
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+}
+
+/// Decodes every complete frame in buf, leaving the bytes of an unfinished one
+fn kiss_async_rx(buf: &mut Vec<u8>, intf: &CspIface) {
+    while let Some((start, end)) = kiss_next_frame(buf) {
+        // The closing FEND may open the next frame
+        let frame = buf[start..=end].to_vec();
+        buf.drain(..end);
+
+        let tnc_port = kiss_tnc_port(&frame);
+        if tnc_port != 0 {
+            debug!("Dropping frame for KISS TNC port {}", tnc_port);
+            CspIfaceStats::inc(&intf.stats.drop);
+            continue;
+        }
+
+        let len = frame.len();
+        let mut rx = KissIntfDataRx::new();
+        rx.max_rx_length = intf.mtu as usize;
+        kiss_rx_deliver(kiss_process_rx(frame, len, &mut rx), intf);
+    }
+
+    // Keep the unfinished frame only
+    match buf.iter().rposition(|b| *b == FEND) {
+        Some(start) if buf.len() - start > kiss_max_frame(intf.mtu as usize) => {
+            warn!("KISS frame too long, discarding");
+            CspIfaceStats::inc(&intf.stats.oversize);
+            buf.clear();
+        }
+        Some(start) => {
+            buf.drain(..start);
+        }
+        None => buf.clear(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn kiss_async_rx_test() {
+        let (tx, rx) = sync_channel(4);
+        let mut intf = CspIface::new(1, 5, "KISS".to_string());
+        intf.rx_channel = Some(tx);
+
+        let id = CspId::new().pri(2).src(3).dst(1).dport(10).sport(40);
+        let frame = kiss_encode(&CspPacket::new().id(id).data(vec![1, 0xC0, 3]));
+
+        let mut buf = vec![0xC0, 0xC0];
+        buf.extend_from_slice(&frame[..5]);
+        kiss_async_rx(&mut buf, &intf);
+        assert!(rx.try_recv().is_err());
+
+        buf.extend_from_slice(&frame[5..]);
+        buf.extend_from_slice(&frame[..3]);
+        kiss_async_rx(&mut buf, &intf);
+        let p = rx.try_recv().unwrap().packet;
+        assert_eq!(p.id, id);
+        assert_eq!(p.data, vec![1, 0xC0, 3]);
+        assert_eq!(buf, frame[..3].to_vec());
+    }
+
+    #[test]
+    fn kiss_async_rx_mtu_test() {
+        let (tx, rx) = sync_channel(4);
+        let mut intf = CspIface::new(1, 5, "KISS".to_string());
+        intf.rx_channel = Some(tx);
+        intf.mtu = 4;
+
+        let mut buf = kiss_encode(&CspPacket::new().data(vec![1; 5]));
+        buf.extend_from_slice(&kiss_encode(&CspPacket::new().data(vec![2; 4])));
+        kiss_async_rx(&mut buf, &intf);
+        assert_eq!(rx.try_recv().unwrap().packet.data, vec![2; 4]);
+        assert!(rx.try_recv().is_err());
+
+        buf.extend_from_slice(&[FEND, 0x00]);
+        buf.extend_from_slice(&[1; 40]);
+        kiss_async_rx(&mut buf, &intf);
+        assert!(buf.is_empty());
+
+        let stats = intf.stats.snapshot();
+        assert_eq!(stats.oversize, 2);
+        assert_eq!(stats.frame, 0);
+    }
+
+    #[test]
+    fn kiss_async_rx_tnc_port_test() {
+        let (tx, rx) = sync_channel(4);
+        let mut intf = CspIface::new(1, 5, "KISS".to_string());
+        intf.rx_channel = Some(tx);
+
+        let mut buf = kiss_encode_port(&CspPacket::new().data(vec![1]), 1);
+        buf.extend_from_slice(&kiss_encode(&CspPacket::new().data(vec![2])));
+        kiss_async_rx(&mut buf, &intf);
+        assert_eq!(rx.try_recv().unwrap().packet.data, vec![2]);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(intf.stats.snapshot().drop, 1);
+    }
+
+    #[test]
+    fn kiss_async_rx_shared_fend_test() {
+        let (tx, rx) = sync_channel(4);
+        let mut intf = CspIface::new(1, 5, "KISS".to_string());
+        intf.rx_channel = Some(tx);
+
+        let first = kiss_encode(&CspPacket::new().data(vec![1]));
+        let second = kiss_encode(&CspPacket::new().data(vec![2]));
+        let mut buf = first.clone();
+        buf.extend_from_slice(&second[1..]);
+
+        kiss_async_rx(&mut buf, &intf);
+        assert_eq!(rx.try_recv().unwrap().packet.data, vec![1]);
+        assert_eq!(rx.try_recv().unwrap().packet.data, vec![2]);
+        assert_eq!(buf, vec![FEND]);
+    }
+}
@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: MIT
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::csp::interfaces::if_kiss::*;
+use crate::csp::types::*;
+
+/// Largest escaped frame accepted: every byte of header, 256 data bytes and CRC escaped
+const KISS_CODEC_MAX_FRAME: usize = 2 * (4 + 256 + 4) + 3;
+
+/**
+ * KISS framing of CSP packets as a tokio codec, wrap any AsyncRead + AsyncWrite with
+ * tokio_util::codec::Framed to get a stream and sink of CspPacket. Only TNC port 0 is used,
+ * frames for other ports and frames that cannot be decoded (bad CRC, too short, too long) are
+ * skipped
+ */
+#[derive(Clone, Debug)]
+pub struct KissCsp {
+    max_frame: usize,
+}
+
+impl KissCsp {
+    pub fn new() -> Self {
+        Self {
+            max_frame: KISS_CODEC_MAX_FRAME,
+        }
+    }
+
+    /// Sets the largest escaped frame accepted, longer frames are discarded
+    pub fn max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+}
+
+impl Default for KissCsp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for KissCsp {
+    type Item = CspPacket;
+    type Error = CspError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<CspPacket>, CspError> {
+        loop {
+            let (start, end) = match kiss_next_frame(src) {
+                Some(f) => f,
+                None => {
+                    match src.iter().rposition(|b| *b == FEND) {
+                        // Keep the unfinished frame only
+                        Some(start) if src.len() - start <= self.max_frame => src.advance(start),
+                        Some(_) => {
+                            warn!("KISS frame too long, discarding");
+                            src.clear();
+                        }
+                        None => src.clear(),
+                    }
+                    return Ok(None);
+                }
+            };
+
+            src.advance(start);
+            // The closing FEND may open the next frame, it stays in src
+            let mut frame = src.split_to(end - start).to_vec();
+            frame.push(FEND);
+            if frame.len() > self.max_frame {
+                warn!("KISS frame too long ({}), discarding", frame.len());
+                continue;
+            }
+
+            let tnc_port = kiss_tnc_port(&frame);
+            if tnc_port != 0 {
+                debug!("Discarding frame for KISS TNC port {}", tnc_port);
+                continue;
+            }
+
+            let len = frame.len();
+            match kiss_process_rx(frame, len, &mut KissIntfDataRx::new()) {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(e) => warn!("Discarding KISS frame: {}", e),
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<CspPacket>, CspError> {
+        let packet = self.decode(src)?;
+        if packet.is_none() && !src.is_empty() {
+            debug!("Discarding unfinished KISS frame at end of stream");
+            src.clear();
+        }
+        Ok(packet)
+    }
+}
+
+impl Encoder<CspPacket> for KissCsp {
+    type Error = CspError;
+
+    fn encode(&mut self, item: CspPacket, dst: &mut BytesMut) -> Result<(), CspError> {
+        dst.extend_from_slice(&kiss_encode(&item));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kiss_codec_test() {
+        let mut codec = KissCsp::new();
+        let id = CspId::new().pri(1).src(4).dst(9).dport(12).sport(50);
+        let packet = CspPacket::new().id(id).data(vec![0xC0, 0xDB, 7]);
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0x55, 0x66]);
+        let mut corrupt = kiss_encode(&packet);
+        let n = corrupt.len();
+        corrupt[n - 2] ^= 0xFF;
+        buf.extend_from_slice(&corrupt);
+        codec.encode(packet.clone(), &mut buf).unwrap();
+        let frame_len = buf.len();
+        codec.encode(packet, &mut buf).unwrap();
+        let full = buf.split_off(frame_len + 4);
+
+        let p = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(p.id, id);
+        assert_eq!(p.data, vec![0xC0, 0xDB, 7]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 4);
+
+        buf.extend_from_slice(&full);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().id, id);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        // The last FEND may open the next frame
+        assert_eq!(&buf[..], &[FEND]);
+
+        buf.extend_from_slice(&[0xC0, 0x00, 1]);
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn kiss_codec_shared_fend_test() {
+        let mut codec = KissCsp::new();
+        let first = kiss_encode(&CspPacket::new().data(vec![1]));
+        let second = kiss_encode(&CspPacket::new().data(vec![2]));
+
+        let mut buf = BytesMut::from(&first[..]);
+        buf.extend_from_slice(&second[1..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().data, vec![1]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().data, vec![2]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn kiss_codec_tnc_port_test() {
+        let mut codec = KissCsp::new();
+        let mut buf = BytesMut::from(&kiss_encode_port(&CspPacket::new().data(vec![1]), 3)[..]);
+        codec
+            .encode(CspPacket::new().data(vec![2]), &mut buf)
+            .unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().data, vec![2]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn kiss_codec_max_frame_test() {
+        let mut codec = KissCsp::new().max_frame(16);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0xC0, 0x00]);
+        buf.extend_from_slice(&[1; 20]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+
+        let packet = CspPacket::new().data(vec![1, 2]);
+        codec.encode(packet, &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().data, vec![1, 2]);
+    }
+}
@@ -1,3 +1,7 @@
 // SPDX-License-Identifier: MIT
 
 pub mod if_kiss;
+#[cfg(feature = "tokio")]
+pub mod if_kiss_async;
+#[cfg(feature = "tokio")]
+pub mod kiss_codec;
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT
+
+use crate::csp::types::*;
+
+/**
+ * Memory accessed by the CMP peek and poke services. Implementations decide what an address means,
+ * no raw memory is ever touched by the library
+ */
+pub trait CspMemoryMap: Send {
+    fn peek(&self, addr: u32, len: usize) -> Result<Vec<u8>, CspError>;
+    fn poke(&mut self, addr: u32, data: &[u8]) -> Result<(), CspError>;
+}
+
+pub struct CspMemRegion {
+    pub name: String,
+    pub addr: u32,
+    pub data: Vec<u8>,
+    pub writable: bool,
+}
+
+/**
+ * Memory map made of named regions, every access must fall completely inside one region
+ */
+#[derive(Default)]
+pub struct CspMemRegions {
+    regions: Vec<CspMemRegion>,
+}
+
+impl CspMemRegion {
+    fn offset(&self, addr: u32, len: usize) -> Option<usize> {
+        let offset = addr.checked_sub(self.addr)? as usize;
+        if offset.checked_add(len)? <= self.data.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+impl CspMemRegions {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    /// Adds a region with its initial contents, overlapping regions are rejected
+    pub fn add(
+        &mut self,
+        name: &str,
+        addr: u32,
+        data: Vec<u8>,
+        writable: bool,
+    ) -> Result<(), CspError> {
+        let end = addr as u64 + data.len() as u64;
+        let overlaps = self
+            .regions
+            .iter()
+            .any(|r| (addr as u64) < r.addr as u64 + r.data.len() as u64 && (r.addr as u64) < end);
+        if overlaps || end > u32::MAX as u64 + 1 {
+            warn!("Invalid memory region {} at {:#010x}", name, addr);
+            return Err(CspError::CspErrInval(format!(
+                "memory region {} at {:#010x}",
+                name, addr
+            )));
+        }
+
+        self.regions.push(CspMemRegion {
+            name: name.to_string(),
+            addr,
+            data,
+            writable,
+        });
+        Ok(())
+    }
+
+    pub fn region(&self, name: &str) -> Option<&CspMemRegion> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+
+    pub fn region_mut(&mut self, name: &str) -> Option<&mut CspMemRegion> {
+        self.regions.iter_mut().find(|r| r.name == name)
+    }
+}
+
+impl CspMemoryMap for CspMemRegions {
+    fn peek(&self, addr: u32, len: usize) -> Result<Vec<u8>, CspError> {
+        for r in &self.regions {
+            if let Some(offset) = r.offset(addr, len) {
+                return Ok(r.data[offset..offset + len].to_vec());
+            }
+        }
+        warn!("Peek outside memory map {:#010x} ({})", addr, len);
+        Err(CspError::inval("peek outside memory map"))
+    }
+
+    fn poke(&mut self, addr: u32, data: &[u8]) -> Result<(), CspError> {
+        for r in self.regions.iter_mut() {
+            if let Some(offset) = r.offset(addr, data.len()) {
+                if !r.writable {
+                    warn!("Poke to read only region {}", r.name);
+                    return Err(CspError::CspErrNotSup);
+                }
+                r.data[offset..offset + data.len()].copy_from_slice(data);
+                return Ok(());
+            }
+        }
+        warn!("Poke outside memory map {:#010x} ({})", addr, data.len());
+        Err(CspError::inval("poke outside memory map"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mem_regions_test() {
+        let mut mem = CspMemRegions::new();
+        mem.add("config", 0x1000, vec![0; 16], true).unwrap();
+        mem.add("flash", 0x8000, (0..32).collect(), false).unwrap();
+        assert!(mem.add("overlap", 0x1008, vec![0; 16], true).is_err());
+
+        assert_eq!(mem.peek(0x8004, 4).unwrap(), vec![4, 5, 6, 7]);
+        assert!(mem.peek(0x801E, 4).is_err());
+        assert!(mem.peek(0x0FFF, 2).is_err());
+
+        mem.poke(0x1002, &[0xAA, 0xBB]).unwrap();
+        assert_eq!(mem.peek(0x1000, 4).unwrap(), vec![0, 0, 0xAA, 0xBB]);
+        assert_eq!(mem.region("config").unwrap().data[3], 0xBB);
+
+        assert!(mem.poke(0x8000, &[1]).is_err());
+        assert!(mem.poke(0x100F, &[1, 2]).is_err());
+    }
+}
@@ -1,11 +1,23 @@
 // SPDX-License-Identifier: MIT
 
+#[cfg(feature = "tokio")]
+pub mod async_csp;
+pub mod bridge;
 pub mod buffer;
+pub mod clock;
+pub mod cmp;
+pub mod config;
 pub mod conn;
+#[allow(clippy::module_inception)]
 pub mod csp;
+pub mod dedup;
 pub mod interface;
 pub mod interfaces;
+pub mod memmap;
+pub mod pcap;
 pub mod port;
 pub mod qfifo;
+pub mod rtable;
 pub mod services;
+pub mod sfp;
 pub mod types;
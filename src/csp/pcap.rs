@@ -0,0 +1,523 @@
+// SPDX-License-Identifier: MIT
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::csp::types::*;
+
+/// LINKTYPE_USER0, packets are stored as the 4 byte CSP header followed by the payload
+pub const CSP_PCAP_LINKTYPE: u16 = 147;
+
+const PCAPNG_SHB: u32 = 0x0A0D0D0A;
+const PCAPNG_IDB: u32 = 0x00000001;
+const PCAPNG_EPB: u32 = 0x00000006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+/// Largest block the reader accepts, the same limit Wireshark uses
+const PCAPNG_MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CspDirection {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Clone)]
+pub struct CspPcapRecord {
+    pub direction: Option<CspDirection>,
+    pub ifname: String,
+    pub timestamp: SystemTime,
+    pub packet: CspPacket,
+}
+
+/**
+ * Writes CSP packets to a pcapng stream, one interface description block per interface name
+ */
+pub struct CspPcapWriter<W: Write> {
+    writer: W,
+    ifaces: Vec<String>,
+}
+
+/**
+ * Reads CSP packets back from a pcapng stream written by CspPcapWriter or any other tool
+ */
+pub struct CspPcapReader<R: Read> {
+    reader: R,
+    ifaces: Vec<(String, u64)>,
+    big_endian: bool,
+}
+
+fn pcap_pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn pcap_put_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let mut hdr = [0u8; 4];
+    LittleEndian::write_u16(&mut hdr[0..2], code);
+    LittleEndian::write_u16(&mut hdr[2..4], value.len() as u16);
+    buf.extend_from_slice(&hdr);
+    buf.extend_from_slice(value);
+    buf.resize(buf.len() + pcap_pad(value.len()), 0);
+}
+
+fn pcap_invalid(msg: &str) -> CspError {
+    CspError::inval(msg)
+}
+
+/// Timestamp units per second for an if_tsresol value, a power of two if the MSB is set
+fn pcap_tsresol(value: u8) -> Result<u64, CspError> {
+    let units = if value & 0x80 == 0 {
+        10u64.checked_pow(value as u32)
+    } else {
+        1u64.checked_shl((value & 0x7F) as u32)
+    };
+    units.ok_or_else(|| pcap_invalid("Invalid timestamp resolution"))
+}
+
+impl CspPcapWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, CspError> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> CspPcapWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, CspError> {
+        let mut body = Vec::new();
+        let mut hdr = [0u8; 16];
+        LittleEndian::write_u32(&mut hdr[0..4], PCAPNG_BYTE_ORDER_MAGIC);
+        LittleEndian::write_u16(&mut hdr[4..6], 1);
+        LittleEndian::write_u16(&mut hdr[6..8], 0);
+        LittleEndian::write_i64(&mut hdr[8..16], -1);
+        body.extend_from_slice(&hdr);
+
+        pcap_write_block(&mut writer, PCAPNG_SHB, &body)?;
+
+        Ok(Self {
+            writer,
+            ifaces: Vec::new(),
+        })
+    }
+
+    fn iface_id(&mut self, ifname: &str) -> Result<u32, CspError> {
+        if let Some(n) = self.ifaces.iter().position(|i| i == ifname) {
+            return Ok(n as u32);
+        }
+
+        let mut body = vec![0u8; 8];
+        LittleEndian::write_u16(&mut body[0..2], CSP_PCAP_LINKTYPE);
+        pcap_put_option(&mut body, IF_NAME, ifname.as_bytes());
+        pcap_put_option(&mut body, IF_TSRESOL, &[6]);
+        pcap_put_option(&mut body, OPT_ENDOFOPT, &[]);
+
+        pcap_write_block(&mut self.writer, PCAPNG_IDB, &body)?;
+        self.ifaces.push(ifname.to_string());
+        Ok(self.ifaces.len() as u32 - 1)
+    }
+
+    pub fn write_packet(
+        &mut self,
+        direction: CspDirection,
+        ifname: &str,
+        timestamp: SystemTime,
+        packet: &CspPacket,
+    ) -> Result<(), CspError> {
+        let iface_id = self.iface_id(ifname)?;
+
+        let mut frame = packet.id.to_bytes().to_vec();
+        frame.extend_from_slice(&packet.data);
+
+        let ts = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut body = vec![0u8; 20];
+        LittleEndian::write_u32(&mut body[0..4], iface_id);
+        LittleEndian::write_u32(&mut body[4..8], (ts >> 32) as u32);
+        LittleEndian::write_u32(&mut body[8..12], ts as u32);
+        LittleEndian::write_u32(&mut body[12..16], frame.len() as u32);
+        LittleEndian::write_u32(&mut body[16..20], frame.len() as u32);
+        body.extend_from_slice(&frame);
+        body.resize(body.len() + pcap_pad(frame.len()), 0);
+
+        let mut flags = [0u8; 4];
+        LittleEndian::write_u32(
+            &mut flags,
+            match direction {
+                CspDirection::Inbound => 1,
+                CspDirection::Outbound => 2,
+            },
+        );
+        pcap_put_option(&mut body, EPB_FLAGS, &flags);
+
+        let id = packet.id;
+        let comment = format!(
+            "pri {} src {} dst {} dport {} sport {} flags {:#04x}",
+            id.pri, id.src, id.dst, id.dport, id.sport, id.flags
+        );
+        pcap_put_option(&mut body, OPT_COMMENT, comment.as_bytes());
+        pcap_put_option(&mut body, OPT_ENDOFOPT, &[]);
+
+        pcap_write_block(&mut self.writer, PCAPNG_EPB, &body)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), CspError> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn pcap_write_block<W: Write>(
+    writer: &mut W,
+    block_type: u32,
+    body: &[u8],
+) -> Result<(), io::Error> {
+    let total = (body.len() + 12) as u32;
+    let mut hdr = [0u8; 8];
+    LittleEndian::write_u32(&mut hdr[0..4], block_type);
+    LittleEndian::write_u32(&mut hdr[4..8], total);
+    writer.write_all(&hdr)?;
+    writer.write_all(body)?;
+    writer.write_all(&total.to_le_bytes())
+}
+
+impl CspPcapReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CspError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CspPcapReader<R> {
+    pub fn new(reader: R) -> Result<Self, CspError> {
+        let mut pcap = Self {
+            reader,
+            ifaces: Vec::new(),
+            big_endian: false,
+        };
+
+        match pcap.read_block()? {
+            Some((PCAPNG_SHB, _)) => Ok(pcap),
+            _ => Err(pcap_invalid("Not a pcapng file")),
+        }
+    }
+
+    fn read_u32(&self, data: &[u8]) -> u32 {
+        if self.big_endian {
+            BigEndian::read_u32(data)
+        } else {
+            LittleEndian::read_u32(data)
+        }
+    }
+
+    fn read_u16(&self, data: &[u8]) -> u16 {
+        if self.big_endian {
+            BigEndian::read_u16(data)
+        } else {
+            LittleEndian::read_u16(data)
+        }
+    }
+
+    /// Returns the next block type and body, None at the end of the stream
+    fn read_block(&mut self) -> Result<Option<(u32, Vec<u8>)>, CspError> {
+        let mut hdr = [0u8; 8];
+        match self.reader.read_exact(&mut hdr) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        if LittleEndian::read_u32(&hdr[0..4]) == PCAPNG_SHB {
+            let mut magic = [0u8; 4];
+            self.reader.read_exact(&mut magic)?;
+            self.big_endian = match LittleEndian::read_u32(&magic) {
+                PCAPNG_BYTE_ORDER_MAGIC => false,
+                m if m.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+                _ => return Err(pcap_invalid("Invalid byte order magic")),
+            };
+            self.ifaces.clear();
+
+            let total = self.read_u32(&hdr[4..8]) as usize;
+            if !(16..=PCAPNG_MAX_BLOCK_LEN).contains(&total) {
+                return Err(pcap_invalid("Invalid block length"));
+            }
+            let mut body = vec![0u8; total - 16];
+            self.reader.read_exact(&mut body)?;
+            self.reader.read_exact(&mut [0u8; 4])?;
+            return Ok(Some((PCAPNG_SHB, body)));
+        }
+
+        let block_type = self.read_u32(&hdr[0..4]);
+        let total = self.read_u32(&hdr[4..8]) as usize;
+        if !(12..=PCAPNG_MAX_BLOCK_LEN).contains(&total) {
+            return Err(pcap_invalid("Invalid block length"));
+        }
+        let mut body = vec![0u8; total - 12];
+        self.reader.read_exact(&mut body)?;
+        self.reader.read_exact(&mut [0u8; 4])?;
+
+        Ok(Some((block_type, body)))
+    }
+
+    fn options(&self, mut data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut opts = Vec::new();
+        while data.len() >= 4 {
+            let code = self.read_u16(&data[0..2]);
+            let len = self.read_u16(&data[2..4]) as usize;
+            if code == OPT_ENDOFOPT || data.len() < 4 + len {
+                break;
+            }
+            opts.push((code, data[4..4 + len].to_vec()));
+            data = &data[(4 + len + pcap_pad(len)).min(data.len())..];
+        }
+        opts
+    }
+
+    /// Returns the next CSP packet in the stream, None at the end of the stream
+    pub fn next_record(&mut self) -> Result<Option<CspPcapRecord>, CspError> {
+        while let Some((block_type, body)) = self.read_block()? {
+            match block_type {
+                PCAPNG_IDB => {
+                    if body.len() < 8 {
+                        return Err(pcap_invalid("Invalid interface block"));
+                    }
+                    let mut name = String::new();
+                    let mut units_per_sec = 1_000_000u64;
+                    for (code, value) in self.options(&body[8..]) {
+                        match code {
+                            IF_NAME => name = String::from_utf8_lossy(&value).to_string(),
+                            IF_TSRESOL if !value.is_empty() => {
+                                units_per_sec = pcap_tsresol(value[0])?
+                            }
+                            _ => {}
+                        }
+                    }
+                    self.ifaces.push((name, units_per_sec));
+                }
+                PCAPNG_EPB => {
+                    if body.len() < 20 {
+                        return Err(pcap_invalid("Invalid packet block"));
+                    }
+                    let iface_id = self.read_u32(&body[0..4]) as usize;
+                    let ts = (self.read_u32(&body[4..8]) as u64) << 32
+                        | self.read_u32(&body[8..12]) as u64;
+                    let caplen = self.read_u32(&body[12..16]) as usize;
+                    if caplen < 4 || body.len() < 20 + caplen {
+                        return Err(pcap_invalid("Invalid CSP packet"));
+                    }
+                    let (ifname, units_per_sec) = self
+                        .ifaces
+                        .get(iface_id)
+                        .cloned()
+                        .ok_or_else(|| pcap_invalid("Unknown interface"))?;
+
+                    let frame = &body[20..20 + caplen];
+                    let opts_start = (20 + caplen + pcap_pad(caplen)).min(body.len());
+                    let mut direction = None;
+                    for (code, value) in self.options(&body[opts_start..]) {
+                        if code == EPB_FLAGS && value.len() == 4 {
+                            direction = match self.read_u32(&value) & 0x3 {
+                                1 => Some(CspDirection::Inbound),
+                                2 => Some(CspDirection::Outbound),
+                                _ => None,
+                            };
+                        }
+                    }
+
+                    let secs = ts / units_per_sec;
+                    let nanos =
+                        (ts % units_per_sec) as u128 * 1_000_000_000 / units_per_sec as u128;
+                    let timestamp = UNIX_EPOCH
+                        .checked_add(Duration::new(secs, nanos as u32))
+                        .ok_or_else(|| pcap_invalid("Invalid timestamp"))?;
+                    let id = CspId::from_bytes(&[frame[0], frame[1], frame[2], frame[3]]);
+
+                    return Ok(Some(CspPcapRecord {
+                        direction,
+                        ifname,
+                        timestamp,
+                        packet: CspPacket::new().id(id).data(frame[4..].to_vec()),
+                    }));
+                }
+                _ => debug!("Skipping pcapng block {:#x}", block_type),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<R: Read> Iterator for CspPcapReader<R> {
+    type Item = Result<CspPcapRecord, CspError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcapng_write_read_test() {
+        let id = CspId::new().pri(2).src(1).dst(8).dport(10).sport(40);
+        let ts = UNIX_EPOCH + Duration::from_micros(1_660_000_000_123_456);
+
+        let mut writer = CspPcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write_packet(
+                CspDirection::Outbound,
+                "KISS",
+                ts,
+                &CspPacket::new().id(id).data(vec![1, 2, 3]),
+            )
+            .unwrap();
+        writer
+            .write_packet(
+                CspDirection::Inbound,
+                "UDP",
+                ts,
+                &CspPacket::new().id(id.flags(CSP_FCRC32)).data(vec![]),
+            )
+            .unwrap();
+        writer
+            .write_packet(
+                CspDirection::Inbound,
+                "KISS",
+                ts + Duration::from_secs(1),
+                &CspPacket::new().id(id).data(vec![0xC0; 9]),
+            )
+            .unwrap();
+        let buf = writer.into_inner();
+        assert_eq!(buf.len() % 4, 0);
+        assert_eq!(&buf[0..4], &[0x0A, 0x0D, 0x0D, 0x0A]);
+
+        let records: Vec<CspPcapRecord> = CspPcapReader::new(&buf[..])
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0].direction, Some(CspDirection::Outbound));
+        assert_eq!(records[0].ifname, "KISS");
+        assert_eq!(records[0].timestamp, ts);
+        assert_eq!(records[0].packet.id, id);
+        assert_eq!(records[0].packet.data, vec![1, 2, 3]);
+
+        assert_eq!(records[1].direction, Some(CspDirection::Inbound));
+        assert_eq!(records[1].ifname, "UDP");
+        assert_eq!(records[1].packet.id.flags, CSP_FCRC32);
+        assert!(records[1].packet.data.is_empty());
+
+        assert_eq!(records[2].ifname, "KISS");
+        assert_eq!(records[2].packet.data, vec![0xC0; 9]);
+
+        assert!(CspPcapReader::new(&buf[4..]).is_err());
+    }
+
+    /// A pcapng stream with one interface using tsresol and one packet at timestamp ts
+    fn pcap_with_tsresol(tsresol: u8, ts: u64) -> Vec<u8> {
+        pcap_with_frame(tsresol, ts, &CspId::new().dst(1).to_bytes())
+    }
+
+    /// Same as pcap_with_tsresol with the captured frame as is, without padding nor options
+    fn pcap_with_frame(tsresol: u8, ts: u64, frame: &[u8]) -> Vec<u8> {
+        let mut buf = CspPcapWriter::new(Vec::new()).unwrap().into_inner();
+
+        let mut body = vec![0u8; 8];
+        LittleEndian::write_u16(&mut body[0..2], CSP_PCAP_LINKTYPE);
+        pcap_put_option(&mut body, IF_TSRESOL, &[tsresol]);
+        pcap_put_option(&mut body, OPT_ENDOFOPT, &[]);
+        pcap_write_block(&mut buf, PCAPNG_IDB, &body).unwrap();
+
+        let mut body = vec![0u8; 20];
+        LittleEndian::write_u32(&mut body[4..8], (ts >> 32) as u32);
+        LittleEndian::write_u32(&mut body[8..12], ts as u32);
+        LittleEndian::write_u32(&mut body[12..16], frame.len() as u32);
+        LittleEndian::write_u32(&mut body[16..20], frame.len() as u32);
+        body.extend_from_slice(frame);
+        pcap_write_block(&mut buf, PCAPNG_EPB, &body).unwrap();
+        buf
+    }
+
+    fn read_timestamp(buf: &[u8]) -> Result<SystemTime, CspError> {
+        let record = CspPcapReader::new(buf).unwrap().next().unwrap()?;
+        Ok(record.timestamp)
+    }
+
+    #[test]
+    fn pcapng_tsresol_test() {
+        let ts = u64::MAX - 1;
+        let expected = UNIX_EPOCH
+            + Duration::new(
+                ts / 1_000_000_000_000,
+                (ts % 1_000_000_000_000 / 1000) as u32,
+            );
+        assert_eq!(
+            read_timestamp(&pcap_with_tsresol(12, ts)).unwrap(),
+            expected
+        );
+
+        let expected = UNIX_EPOCH + Duration::from_millis(2500);
+        assert_eq!(
+            read_timestamp(&pcap_with_tsresol(0x80 | 10, 2560)).unwrap(),
+            expected
+        );
+
+        assert!(read_timestamp(&pcap_with_tsresol(19, 1)).is_ok());
+        assert!(read_timestamp(&pcap_with_tsresol(20, 1)).is_err());
+        assert!(read_timestamp(&pcap_with_tsresol(0x80 | 64, 1)).is_err());
+
+        for tsresol in [0, 0x80] {
+            assert!(matches!(
+                read_timestamp(&pcap_with_tsresol(tsresol, u64::MAX)),
+                Err(CspError::CspErrInval(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn pcapng_unpadded_packet_test() {
+        let mut frame = CspId::new().dst(1).to_bytes().to_vec();
+        frame.push(0xAB);
+        let buf = pcap_with_frame(6, 1, &frame);
+
+        let record = CspPcapReader::new(&buf[..])
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.packet.id.dst, 1);
+        assert_eq!(record.packet.data, vec![0xAB]);
+        assert_eq!(record.direction, None);
+    }
+
+    #[test]
+    fn pcapng_block_len_test() {
+        let mut buf = CspPcapWriter::new(Vec::new()).unwrap().into_inner();
+        let mut hdr = [0u8; 8];
+        LittleEndian::write_u32(&mut hdr[0..4], PCAPNG_EPB);
+        LittleEndian::write_u32(&mut hdr[4..8], u32::MAX);
+        buf.extend_from_slice(&hdr);
+
+        let mut reader = CspPcapReader::new(&buf[..]).unwrap();
+        assert!(matches!(
+            reader.next_record(),
+            Err(CspError::CspErrInval(_))
+        ));
+    }
+}
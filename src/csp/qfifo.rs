@@ -1,5 +1,81 @@
 // SPDX-License-Identifier: MIT
 
+use std::sync::mpsc::TrySendError;
+
+use crate::csp::interface::*;
+use crate::csp::types::*;
+
 pub fn csp_qfifo_init() {
     info!("CSP qfifo init");
 }
+
+/// Hands a packet received by an interface driver to the router, updating the interface counters
+pub fn csp_qfifo_write(packet: CspPacket, iface: &CspIface) {
+    let len = packet.data.len() as u32;
+    if len > iface.mtu as u32 {
+        warn!(
+            "Dropping packet of {} bytes, MTU of {} is {}",
+            len, iface.name, iface.mtu
+        );
+        CspIfaceStats::inc(&iface.stats.oversize);
+        return;
+    }
+
+    let channel = match &iface.rx_channel {
+        Some(c) => c,
+        None => {
+            error!("No RX fifo for {}", iface.name);
+            CspIfaceStats::inc(&iface.stats.drop);
+            return;
+        }
+    };
+
+    let fifo_pkt = CspFIFO {
+        iface: iface.clone(),
+        packet,
+    };
+
+    match channel.try_send(fifo_pkt) {
+        Ok(()) => {
+            CspIfaceStats::inc(&iface.stats.rx);
+            CspIfaceStats::add(&iface.stats.rxbytes, len);
+        }
+        Err(TrySendError::Full(_)) => {
+            warn!("RX fifo full, dropping packet from {}", iface.name);
+            CspIfaceStats::inc(&iface.stats.drop);
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            error!("RX fifo closed for {}", iface.name);
+            CspIfaceStats::inc(&iface.stats.drop);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn qfifo_write_test() {
+        let mut intf = CspIface::new(1, 5, "KISS".to_string());
+        csp_qfifo_write(CspPacket::new(), &intf);
+        assert_eq!(intf.stats.snapshot().drop, 1);
+
+        let (tx, rx) = sync_channel(1);
+        intf.rx_channel = Some(tx);
+        csp_qfifo_write(CspPacket::new().data(vec![1, 2, 3]), &intf);
+        csp_qfifo_write(CspPacket::new().data(vec![1, 2, 3]), &intf);
+
+        let stats = intf.stats.snapshot();
+        assert_eq!(stats.rx, 1);
+        assert_eq!(stats.rxbytes, 3);
+        assert_eq!(stats.drop, 2);
+        assert_eq!(rx.recv().unwrap().packet.data, vec![1, 2, 3]);
+
+        intf.mtu = 2;
+        csp_qfifo_write(CspPacket::new().data(vec![1, 2, 3]), &intf);
+        assert_eq!(intf.stats.snapshot().oversize, 1);
+        assert!(rx.try_recv().is_err());
+    }
+}
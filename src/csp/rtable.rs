@@ -0,0 +1,234 @@
+// SPDX-License-Identifier: MIT
+
+use std::fmt;
+
+use crate::csp::types::CspError;
+
+/// Via address meaning "deliver directly to the destination"
+pub const CSP_NO_VIA_ADDRESS: u16 = 0xFF;
+/// Number of bits of a CSP 1 node address
+pub const CSP_ID_HOST_SIZE: u16 = 5;
+pub const CSP_BROADCAST_ADDR: u16 = (1 << CSP_ID_HOST_SIZE) - 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CspRoute {
+    pub address: u16,
+    pub netmask: u16,
+    pub iface: String,
+    pub via: u16,
+}
+
+/**
+ * Routing table, routes are matched by longest prefix (CIDR style) and refer to interfaces by name
+ */
+#[derive(Clone, Default)]
+pub struct CspRtable {
+    routes: Vec<CspRoute>,
+}
+
+impl CspRoute {
+    fn matches(&self, address: u16) -> bool {
+        let mask = csp_rtable_mask(self.netmask);
+        (address & mask) == (self.address & mask)
+    }
+
+    /// Parses one entry of the text format, "address[/netmask] interface [via]"
+    fn parse(entry: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = entry.split_whitespace().collect();
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err("expected 'address[/netmask] interface [via]'".to_string());
+        }
+
+        let max_address = (1 << CSP_ID_HOST_SIZE) - 1;
+        let number = |s: &str, what: &str, max: u16| match s.parse::<u16>() {
+            Ok(n) if n <= max => Ok(n),
+            _ => Err(format!("invalid {} '{}'", what, s)),
+        };
+
+        let (address, netmask) = match fields[0].split_once('/') {
+            Some((a, m)) => (
+                number(a, "address", max_address)?,
+                number(m, "netmask", CSP_ID_HOST_SIZE)?,
+            ),
+            None => (number(fields[0], "address", max_address)?, CSP_ID_HOST_SIZE),
+        };
+
+        let via = match fields.get(2) {
+            Some(v) => number(v, "via address", max_address)?,
+            None => CSP_NO_VIA_ADDRESS,
+        };
+
+        Ok(Self {
+            address,
+            netmask,
+            iface: fields[1].to_string(),
+            via,
+        })
+    }
+}
+
+impl fmt::Display for CspRoute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{} {}", self.address, self.netmask, self.iface)?;
+        if self.via != CSP_NO_VIA_ADDRESS {
+            write!(f, " {}", self.via)?;
+        }
+        Ok(())
+    }
+}
+
+fn csp_rtable_mask(netmask: u16) -> u16 {
+    let host_mask = (1 << CSP_ID_HOST_SIZE) - 1;
+    if netmask == 0 {
+        0
+    } else {
+        (host_mask << CSP_ID_HOST_SIZE.saturating_sub(netmask)) & host_mask
+    }
+}
+
+impl CspRtable {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Adds a route, replacing any existing route for the same address/netmask
+    pub fn set(
+        &mut self,
+        address: u16,
+        netmask: u16,
+        iface: &str,
+        via: u16,
+    ) -> Result<(), CspError> {
+        if netmask > CSP_ID_HOST_SIZE {
+            return Err(CspError::CspErrInval(format!(
+                "invalid netmask {}",
+                netmask
+            )));
+        }
+
+        let route = CspRoute {
+            address,
+            netmask,
+            iface: iface.to_string(),
+            via,
+        };
+
+        match self
+            .routes
+            .iter_mut()
+            .find(|r| r.address == address && r.netmask == netmask)
+        {
+            Some(r) => *r = route,
+            None => self.routes.push(route),
+        }
+        Ok(())
+    }
+
+    pub fn find(&self, address: u16) -> Option<&CspRoute> {
+        self.routes
+            .iter()
+            .filter(|r| r.matches(address))
+            .max_by_key(|r| r.netmask)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, CspRoute> {
+        self.routes.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.routes.clear();
+    }
+
+    /// Parses a routing table in text format, e.g. "0/0 CAN, 8 KISS, 10/2 I2C 10"
+    pub fn parse(table: &str) -> Result<Vec<CspRoute>, CspError> {
+        table
+            .split(',')
+            .enumerate()
+            .filter(|(_, entry)| !entry.trim().is_empty())
+            .map(|(n, entry)| {
+                CspRoute::parse(entry).map_err(|reason| {
+                    CspError::CspErrInval(format!(
+                        "route entry {} '{}': {}",
+                        n + 1,
+                        entry.trim(),
+                        reason
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    /// Prints the routing table in the format accepted by parse
+    pub fn save(&self) -> String {
+        self.routes
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtable_find_test() {
+        let mut rtable = CspRtable::new();
+        assert!(rtable.find(8).is_none());
+
+        rtable.set(0, 0, "KISS", CSP_NO_VIA_ADDRESS).unwrap();
+        rtable.set(8, 3, "CAN", CSP_NO_VIA_ADDRESS).unwrap();
+        rtable.set(10, CSP_ID_HOST_SIZE, "I2C", 12).unwrap();
+
+        assert_eq!(rtable.find(1).unwrap().iface, "KISS");
+        assert_eq!(rtable.find(9).unwrap().iface, "CAN");
+        assert_eq!(rtable.find(10).unwrap().iface, "I2C");
+        assert_eq!(rtable.find(10).unwrap().via, 12);
+
+        rtable
+            .set(10, CSP_ID_HOST_SIZE, "CAN", CSP_NO_VIA_ADDRESS)
+            .unwrap();
+        assert_eq!(rtable.find(10).unwrap().iface, "CAN");
+        assert_eq!(rtable.iter().count(), 3);
+
+        assert!(matches!(
+            rtable.set(8, CSP_ID_HOST_SIZE + 1, "CAN", CSP_NO_VIA_ADDRESS),
+            Err(CspError::CspErrInval(_))
+        ));
+        assert_eq!(rtable.iter().count(), 3);
+        assert_eq!(csp_rtable_mask(CSP_ID_HOST_SIZE + 1), CSP_BROADCAST_ADDR);
+    }
+
+    #[test]
+    fn rtable_parse_save_test() {
+        let routes = CspRtable::parse("0/0 CAN, 8 KISS, 10/2 I2C 10,").unwrap();
+        assert_eq!(routes.len(), 3);
+        assert_eq!(
+            routes[1],
+            CspRoute {
+                address: 8,
+                netmask: CSP_ID_HOST_SIZE,
+                iface: "KISS".to_string(),
+                via: CSP_NO_VIA_ADDRESS,
+            }
+        );
+        assert_eq!(routes[2].netmask, 2);
+        assert_eq!(routes[2].via, 10);
+
+        let mut rtable = CspRtable::new();
+        for r in routes {
+            rtable.set(r.address, r.netmask, &r.iface, r.via).unwrap();
+        }
+        assert_eq!(rtable.save(), "0/0 CAN, 8/5 KISS, 10/2 I2C 10");
+        assert_eq!(CspRtable::parse(&rtable.save()).unwrap().len(), 3);
+
+        assert!(CspRtable::parse("").unwrap().is_empty());
+
+        let err = CspRtable::parse("0/0 CAN, 40 KISS").unwrap_err();
+        assert!(err.to_string().contains("entry 2 '40 KISS'"));
+        assert!(CspRtable::parse("8/6 KISS").is_err());
+        assert!(CspRtable::parse("8 KISS 1 2").is_err());
+        assert!(CspRtable::parse("KISS 8").is_err());
+    }
+}
@@ -1,19 +1,24 @@
 // SPDX-License-Identifier: MIT
 
+use byteorder::ByteOrder;
+use std::time::Duration;
+
 use crate::csp::conn::*;
 use crate::csp::csp::*;
 use crate::csp::types::*;
 
+pub const CSP_REBOOT_MAGIC: u32 = 0x80078007;
+pub const CSP_REBOOT_SHUTDOWN_MAGIC: u32 = 0xD1E5529A;
+
 impl CSP {
-    pub fn csp_ping(self, node: u16, timeout: u32, conn_options: u8) -> Result<(), CspError> {
-        let mut conn = csp_connect(
+    pub fn csp_ping(&self, node: u16, timeout: u32, conn_options: u8) -> Result<(), CspError> {
+        let mut conn = self.csp_connect(
             CspPriorities::CspPrioNormal,
             node,
             CspServices::CspPing as u8,
             timeout,
             conn_options,
-        )
-        .unwrap();
+        )?;
 
         let mut packet = CspPacket::new();
 
@@ -21,12 +26,197 @@ impl CSP {
             *a = idx as u8;
         }
 
-        self.csp_send(&mut conn, &mut packet).unwrap();
-
-        // let _spacket = self.csp_read(&mut conn, timeout).unwrap();
+        self.csp_send(&mut conn, &mut packet)?;
 
-        // check echo
+        let reply = conn.read(Duration::from_millis(timeout as u64))?;
+        if reply.data != packet.data {
+            warn!("Ping reply from {} does not match", node);
+            return Err(CspError::inval("ping reply does not match"));
+        }
 
         Ok(())
     }
+
+    /// Asks node to reboot
+    pub fn csp_reboot(&self, node: u16) -> Result<(), CspError> {
+        self.csp_send_magic(node, CSP_REBOOT_MAGIC)
+    }
+
+    /// Asks node to shutdown
+    pub fn csp_shutdown(&self, node: u16) -> Result<(), CspError> {
+        self.csp_send_magic(node, CSP_REBOOT_SHUTDOWN_MAGIC)
+    }
+
+    fn csp_send_magic(&self, node: u16, magic: u32) -> Result<(), CspError> {
+        let mut conn = csp_connect(
+            CspPriorities::CspPrioNormal,
+            node,
+            CspServices::CspReboot as u8,
+            0,
+            0,
+        )?;
+
+        let mut data = vec![0u8; 4];
+        byteorder::BigEndian::write_u32(&mut data, magic);
+        let mut packet = CspPacket::new().data(data);
+
+        self.csp_send(&mut conn, &mut packet)
+    }
+
+    /// Handles a packet received on one of the standard service ports
+    pub fn csp_service_handler(&self, packet: CspPacket) {
+        match packet.id.dport {
+            p if p == CspServices::CspCMP as u8 => {
+                if let Some(reply) = self.csp_cmp_handler(&packet) {
+                    self.csp_service_reply(&packet, reply);
+                }
+            }
+            p if p == CspServices::CspPing as u8 => {
+                let data = packet.data.clone();
+                self.csp_service_reply(&packet, data);
+            }
+            p if p == CspServices::CspReboot as u8 => self.csp_reboot_handler(&packet),
+            p => debug!("No service handler for port {}", p),
+        }
+    }
+
+    fn csp_service_reply(&self, request: &CspPacket, data: Vec<u8>) {
+        let mut conn = CspConnection::new();
+        conn.state = ConnState::ConnOpen;
+        conn.idout = CspId::new()
+            .pri(request.id.pri)
+            .src(request.id.dst)
+            .dst(request.id.src)
+            .dport(request.id.sport)
+            .sport(request.id.dport);
+
+        let mut reply = CspPacket::new().data(data);
+        if let Err(e) = self.csp_send(&mut conn, &mut reply) {
+            warn!("Service reply to {} failed: {}", request.id.src, e);
+        }
+    }
+
+    fn csp_reboot_handler(&self, packet: &CspPacket) {
+        if packet.data.len() < 4 {
+            warn!("Invalid reboot request length {}", packet.data.len());
+            return;
+        }
+
+        let magic = byteorder::BigEndian::read_u32(&packet.data[0..4]);
+        let res = match magic {
+            CSP_REBOOT_MAGIC => self.csp_sys_reboot(),
+            CSP_REBOOT_SHUTDOWN_MAGIC => self.csp_sys_shutdown(),
+            _ => {
+                warn!("Invalid reboot magic {:#010x}", magic);
+                return;
+            }
+        };
+        if let Err(e) = res {
+            warn!(
+                "Reboot request {:#010x} from {} failed: {}",
+                magic, packet.id.src, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::interface::*;
+    use crate::csp::qfifo::csp_qfifo_write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn magic_packet(magic: u32) -> CspPacket {
+        let mut data = vec![0u8; 4];
+        byteorder::BigEndian::write_u32(&mut data, magic);
+        CspPacket::new()
+            .id(CspId::new().dport(CspServices::CspReboot as u8))
+            .data(data)
+    }
+
+    #[test]
+    fn reboot_handler_test() {
+        let rebooted = Arc::new(AtomicBool::new(false));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let csp = CSP::new();
+        let flag = rebooted.clone();
+        csp.csp_sys_set_reboot(Box::new(move || flag.store(true, Ordering::SeqCst)));
+        let flag = shutdown.clone();
+        csp.csp_sys_set_shutdown(Box::new(move || flag.store(true, Ordering::SeqCst)));
+
+        csp.csp_service_handler(magic_packet(0x12345678));
+        assert!(!rebooted.load(Ordering::SeqCst));
+        assert!(!shutdown.load(Ordering::SeqCst));
+
+        csp.csp_service_handler(magic_packet(CSP_REBOOT_MAGIC));
+        assert!(rebooted.load(Ordering::SeqCst));
+        assert!(!shutdown.load(Ordering::SeqCst));
+
+        csp.csp_service_handler(magic_packet(CSP_REBOOT_SHUTDOWN_MAGIC));
+        assert!(shutdown.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reboot_no_hook_test() {
+        let csp = CSP::new();
+        assert!(matches!(csp.csp_sys_reboot(), Err(CspError::CspErrNotSup)));
+        assert!(matches!(
+            csp.csp_sys_shutdown(),
+            Err(CspError::CspErrNotSup)
+        ));
+    }
+
+    struct LoopIntf {
+        intf: CspIface,
+    }
+
+    impl NextHop for LoopIntf {
+        fn next_hop(
+            &self,
+            _via: u16,
+            packet: &mut CspPacket,
+            _from_me: bool,
+        ) -> Result<(), CspError> {
+            csp_qfifo_write(packet.clone(), &self.intf);
+            Ok(())
+        }
+
+        fn iface(&self) -> &CspIface {
+            &self.intf
+        }
+    }
+
+    #[test]
+    fn ping_test() {
+        let csp = CSP::new();
+        let mut intf = CspIface::new(1, 5, "LOOP".to_string());
+        intf.rx_channel = Some(csp.get_rx_channel());
+        csp.add_interface(Box::new(LoopIntf { intf })).unwrap();
+
+        assert!(matches!(
+            csp.csp_ping(1, 50, 0),
+            Err(CspError::CspErrTimedOut)
+        ));
+
+        let running = Arc::new(AtomicBool::new(true));
+        let server = {
+            let csp = csp.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                while running.load(Ordering::SeqCst) {
+                    if let Ok(packet) = csp.csp_read(Duration::from_millis(20)) {
+                        csp.csp_service_handler(packet);
+                    }
+                }
+            })
+        };
+
+        csp.csp_ping(1, 1000, 0).unwrap();
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+        assert_eq!(csp.csp_conn_count(), 0);
+    }
 }
@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: MIT
+
+use byteorder::ByteOrder;
+use std::time::Duration;
+
+use crate::csp::csp::*;
+use crate::csp::types::*;
+
+/// Size of the SFP trailer (offset and total size) appended to each fragment
+pub const CSP_SFP_HEADER_LEN: usize = 8;
+
+impl CSP {
+    /// Sends data over conn split in fragments of at most mtu bytes of payload
+    pub fn csp_sfp_send(
+        &self,
+        conn: &mut CspConnection,
+        data: &[u8],
+        mtu: usize,
+    ) -> Result<(), CspError> {
+        if mtu == 0 {
+            warn!("SFP: invalid MTU");
+            return Err(CspError::inval("SFP MTU must not be zero"));
+        }
+
+        let totalsize = data.len() as u32;
+        let flags = conn.idout.flags;
+        conn.idout.flags |= CSP_FFRAG;
+
+        let mut res = Ok(());
+        for (n, chunk) in data.chunks(mtu).enumerate() {
+            let offset = (n * mtu) as u32;
+            debug!(
+                "SFP: sending {} bytes at {} of {}",
+                chunk.len(),
+                offset,
+                totalsize
+            );
+
+            let mut frag = chunk.to_vec();
+            let mut trailer = [0u8; CSP_SFP_HEADER_LEN];
+            byteorder::BigEndian::write_u32(&mut trailer[0..4], offset);
+            byteorder::BigEndian::write_u32(&mut trailer[4..8], totalsize);
+            frag.extend_from_slice(&trailer);
+
+            let mut packet = CspPacket::new().data(frag);
+            if let Err(e) = self.csp_send(conn, &mut packet) {
+                warn!("SFP: send failed at offset {}: {}", offset, e);
+                res = Err(e);
+                break;
+            }
+        }
+
+        conn.idout.flags = flags;
+        res
+    }
+
+    /// Receives and reassembles data sent with csp_sfp_send, timeout applies to each fragment
+    pub fn csp_sfp_recv(&self, conn: &CspConnection, timeout: u32) -> Result<Vec<u8>, CspError> {
+        let mut data: Vec<u8> = Vec::new();
+        let mut totalsize: Option<usize> = None;
+
+        loop {
+            let timeout = Duration::from_millis(timeout as u64);
+            let packet = match conn.queue {
+                Some(_) => conn.read(timeout)?,
+                None => self.csp_read(timeout)?,
+            };
+
+            if packet.id.src != conn.idout.dst
+                || packet.id.sport != conn.idout.dport
+                || packet.id.dport != conn.idout.sport
+            {
+                debug!("SFP: discarding packet {:?}", packet.id);
+                continue;
+            }
+
+            if packet.id.flags & CSP_FFRAG == 0 {
+                warn!("SFP: missing fragment flag");
+                return Err(CspError::CspErrSfp);
+            }
+
+            let len = packet.data.len();
+            if len < CSP_SFP_HEADER_LEN {
+                warn!("SFP: fragment too short ({})", len);
+                return Err(CspError::CspErrSfp);
+            }
+
+            let payload = &packet.data[..len - CSP_SFP_HEADER_LEN];
+            let offset = byteorder::BigEndian::read_u32(&packet.data[len - 8..len - 4]) as usize;
+            let size = byteorder::BigEndian::read_u32(&packet.data[len - 4..]) as usize;
+
+            if *totalsize.get_or_insert(size) != size {
+                warn!("SFP: total size changed from {:?} to {}", totalsize, size);
+                return Err(CspError::CspErrSfp);
+            }
+
+            if offset != data.len() || offset + payload.len() > size {
+                warn!(
+                    "SFP: unexpected fragment at {} ({} received)",
+                    offset,
+                    data.len()
+                );
+                return Err(CspError::CspErrSfp);
+            }
+
+            data.extend_from_slice(payload);
+            debug!("SFP: received {} of {}", data.len(), size);
+
+            if data.len() >= size {
+                return Ok(data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csp::config::CspConfig;
+    use crate::csp::conn::*;
+    use crate::csp::interface::*;
+
+    struct LoopIntf {
+        intf: CspIface,
+    }
+
+    impl NextHop for LoopIntf {
+        fn next_hop(
+            &self,
+            _via: u16,
+            packet: &mut CspPacket,
+            _from_me: bool,
+        ) -> Result<(), CspError> {
+            let fifo = CspFIFO {
+                iface: self.intf.clone(),
+                packet: packet.clone(),
+            };
+            self.intf
+                .rx_channel
+                .as_ref()
+                .unwrap()
+                .send(fifo)
+                .map_err(|_| CspError::CspErrReset)
+        }
+
+        fn iface(&self) -> &CspIface {
+            &self.intf
+        }
+    }
+
+    fn loop_csp() -> CSP {
+        let csp = CSP::new();
+        let mut intf = CspIface::new(0, 5, "LOOP".to_string());
+        intf.rx_channel = Some(csp.get_rx_channel());
+        csp.add_interface(Box::new(LoopIntf { intf })).unwrap();
+        csp
+    }
+
+    #[test]
+    fn sfp_mtu_test() {
+        let data: Vec<u8> = (0..300).map(|n| n as u8).collect();
+        for fragmentation in [false, true] {
+            let csp = CSP::with_config(CspConfig::new().fragmentation(fragmentation)).unwrap();
+            let mut intf = CspIface::new(0, 5, "LOOP".to_string());
+            intf.mtu = 100;
+            intf.rx_channel = Some(csp.get_rx_channel());
+            let stats = intf.stats.clone();
+            csp.add_interface(Box::new(LoopIntf { intf })).unwrap();
+
+            let mut conn = csp_connect(CspPriorities::CspPrioNormal, 0, 20, 100, 0).unwrap();
+            let mut packet = CspPacket::new().data(data.clone());
+            let res = csp.csp_send(&mut conn, &mut packet);
+            if !fragmentation {
+                assert!(matches!(res, Err(CspError::CspErrInval(_))));
+                assert_eq!(stats.snapshot().oversize, 1);
+                continue;
+            }
+
+            res.unwrap();
+            assert_eq!(stats.snapshot().tx, 4);
+            let mut server = CspConnection::new();
+            server.idout = CspId::new()
+                .dst(csp.csp_get_address() as u8)
+                .dport(conn.idout.sport)
+                .sport(conn.idout.dport);
+            assert_eq!(csp.csp_sfp_recv(&server, 100).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn sfp_send_recv_test() {
+        let csp = loop_csp();
+        let data: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+
+        let mut conn = csp_connect(CspPriorities::CspPrioNormal, 0, 20, 100, 0).unwrap();
+        csp.csp_sfp_send(&mut conn, &data, 100).unwrap();
+        assert_eq!(conn.idout.flags & CSP_FFRAG, 0);
+
+        let mut server = CspConnection::new();
+        server.idout = CspId::new()
+            .dst(csp.csp_get_address() as u8)
+            .dport(conn.idout.sport)
+            .sport(conn.idout.dport);
+
+        let received = csp.csp_sfp_recv(&server, 100).unwrap();
+        assert_eq!(received, data);
+
+        assert!(matches!(
+            csp.csp_sfp_recv(&server, 10),
+            Err(CspError::CspErrTimedOut)
+        ));
+    }
+
+    #[test]
+    fn sfp_missing_fragment_test() {
+        let csp = loop_csp();
+        let tx = csp.get_rx_channel();
+
+        let id = CspId::new().src(3).sport(20).dport(40).flags(CSP_FFRAG);
+        let fragment = |offset: u32| {
+            let mut data = vec![0u8; 10];
+            data.extend_from_slice(&offset.to_be_bytes());
+            data.extend_from_slice(&30u32.to_be_bytes());
+            CspFIFO {
+                iface: CspIface::new(0, 5, "LOOP".to_string()),
+                packet: CspPacket::new().id(id).data(data),
+            }
+        };
+        tx.send(fragment(0)).unwrap();
+        tx.send(fragment(20)).unwrap();
+
+        let mut server = CspConnection::new();
+        server.idout = CspId::new().dst(3).dport(20).sport(40);
+        assert!(matches!(
+            csp.csp_sfp_recv(&server, 100),
+            Err(CspError::CspErrSfp)
+        ));
+    }
+}
@@ -1,19 +1,31 @@
 // SPDX-License-Identifier: MIT
 
 use crc::{Crc, CRC_32_ISCSI};
+use std::fmt;
 use std::io;
 
+use crate::csp::conn::CspConnQueue;
 use crate::csp::interface::*;
 
 pub const CSPCRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 
+// CSP header flags
+pub const CSP_FRES1: u8 = 0x80;
+pub const CSP_FRES2: u8 = 0x40;
+pub const CSP_FRES3: u8 = 0x20;
+pub const CSP_FFRAG: u8 = 0x10; // Packet is an SFP fragment
+pub const CSP_FHMAC: u8 = 0x08;
+pub const CSP_FXTEA: u8 = 0x04;
+pub const CSP_FRDP: u8 = 0x02;
+pub const CSP_FCRC32: u8 = 0x01;
+
 pub fn csp_send_direct_iface<Intf>(
     _idout: &CspId,
     packet: &mut CspPacket,
     iface: &mut Intf,
     via: u16,
     from_me: bool,
-) -> Result<(), io::Error>
+) -> Result<(), CspError>
 where
     Intf: NextHop,
 {
@@ -46,6 +58,7 @@ pub struct CspConnection {
     pub opts: u32,
     pub state: ConnState,
     pub idout: CspId,
+    pub(crate) queue: Option<CspConnQueue>,
 }
 
 pub struct CspFIFO {
@@ -53,12 +66,111 @@ pub struct CspFIFO {
     pub packet: CspPacket,
 }
 
-#[allow(dead_code)]
+/**
+ * Errors returned by the library, mirroring the libcsp error codes
+ */
 #[derive(Debug)]
 pub enum CspError {
-    CspNoError,
-    CspError,
-    CspNoPacket,
+    CspErrNoMem,
+    CspErrInval(String),
+    CspErrTimedOut,
+    CspErrUsed,
+    CspErrNotSup,
+    CspErrBusy,
+    CspErrAlready,
+    CspErrReset,
+    CspErrNoBufs,
+    CspErrTx,
+    CspErrDriver(io::Error),
+    CspErrAgain,
+    /// No libcsp equivalent, reported to C callers as CSP_ERR_TX like libcsp does
+    CspErrNoRoute,
+    /// No libcsp equivalent, reported to C callers as CSP_ERR_RESET
+    CspErrConnClosed,
+    CspErrHmac,
+    CspErrXtea,
+    CspErrCrc32,
+    CspErrSfp,
+}
+
+impl CspError {
+    pub fn inval(msg: &str) -> Self {
+        CspError::CspErrInval(msg.to_string())
+    }
+
+    /// libcsp error code (CSP_ERR_*), errors without one map to the closest libcsp code
+    pub fn code(&self) -> i32 {
+        match self {
+            CspError::CspErrNoMem => -1,
+            CspError::CspErrInval(_) => -2,
+            CspError::CspErrTimedOut => -3,
+            CspError::CspErrUsed => -4,
+            CspError::CspErrNotSup => -5,
+            CspError::CspErrBusy => -6,
+            CspError::CspErrAlready => -7,
+            CspError::CspErrReset => -8,
+            CspError::CspErrNoBufs => -9,
+            CspError::CspErrTx => -10,
+            CspError::CspErrDriver(_) => -11,
+            CspError::CspErrAgain => -12,
+            CspError::CspErrNoRoute => -10,
+            CspError::CspErrConnClosed => -8,
+            CspError::CspErrHmac => -100,
+            CspError::CspErrXtea => -101,
+            CspError::CspErrCrc32 => -102,
+            CspError::CspErrSfp => -103,
+        }
+    }
+}
+
+impl fmt::Display for CspError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CspError::CspErrNoMem => write!(f, "not enough memory"),
+            CspError::CspErrInval(msg) => write!(f, "invalid argument: {}", msg),
+            CspError::CspErrTimedOut => write!(f, "operation timed out"),
+            CspError::CspErrUsed => write!(f, "resource already in use"),
+            CspError::CspErrNotSup => write!(f, "operation not supported"),
+            CspError::CspErrBusy => write!(f, "device or resource busy"),
+            CspError::CspErrAlready => write!(f, "already in progress"),
+            CspError::CspErrReset => write!(f, "connection reset"),
+            CspError::CspErrNoBufs => write!(f, "no more buffer space available"),
+            CspError::CspErrTx => write!(f, "transmission failed"),
+            CspError::CspErrDriver(e) => write!(f, "error in driver layer: {}", e),
+            CspError::CspErrAgain => write!(f, "resource temporarily unavailable"),
+            CspError::CspErrNoRoute => write!(f, "no route to destination"),
+            CspError::CspErrConnClosed => write!(f, "connection closed"),
+            CspError::CspErrHmac => write!(f, "HMAC failed"),
+            CspError::CspErrXtea => write!(f, "XTEA failed"),
+            CspError::CspErrCrc32 => write!(f, "CRC32 failed"),
+            CspError::CspErrSfp => write!(f, "SFP protocol error or inconsistency"),
+        }
+    }
+}
+
+impl std::error::Error for CspError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CspError::CspErrDriver(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CspError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => CspError::CspErrTimedOut,
+            io::ErrorKind::OutOfMemory => CspError::CspErrNoMem,
+            _ => CspError::CspErrDriver(e),
+        }
+    }
+}
+
+impl From<serialport::Error> for CspError {
+    fn from(e: serialport::Error) -> Self {
+        io::Error::from(e).into()
+    }
 }
 
 pub enum CspServices {
@@ -96,6 +208,25 @@ impl CspPacket {
         self
     }
 
+    /// Checks and removes the CRC32 appended by csp_crc32_append
+    pub fn csp_crc32_verify(&mut self) -> Result<(), CspError> {
+        let len = match self.data.len().checked_sub(4) {
+            Some(len) => len,
+            None => return Err(CspError::CspErrCrc32),
+        };
+        let crc = u32::from_be_bytes([
+            self.data[len],
+            self.data[len + 1],
+            self.data[len + 2],
+            self.data[len + 3],
+        ]);
+        if CSPCRC32.checksum(&self.data[..len]) != crc {
+            return Err(CspError::CspErrCrc32);
+        }
+        self.data.truncate(len);
+        Ok(())
+    }
+
     pub fn csp_crc32_append(&mut self) {
         let calc_crc = CSPCRC32.checksum(&self.data);
 
@@ -157,6 +288,28 @@ impl CspId {
         self.sport = sport;
         self
     }
+
+    // CSP 1.0
+    // | Byte0 | Byte 1 | Byte 2 | Byte 3 |
+    // | 2 PRIO | 5 SOURCE | 5 DESTINATION | 6 DESTINATION PORT | 6 SOURCE PORT | 8 FLAGS |
+    pub fn from_bytes(bytes: &[u8; 4]) -> Self {
+        Self {
+            sport: bytes[2] & 0x3F,
+            dport: (bytes[1] & 0x0F) << 2 | (bytes[2] & 0xC0) >> 6,
+            dst: (bytes[1] >> 4) | (bytes[0] & 0x01) << 4,
+            src: (bytes[0] >> 1) & 0x1F,
+            pri: (bytes[0] >> 6) & 0x03,
+            flags: bytes[3],
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let byte0 = (self.pri << 6) | (self.src & 0x1F) << 1 | (self.dst & 0x10) >> 4;
+        let byte1 = ((self.dport & 0x3C) >> 2) | (self.dst & 0x0F) << 4;
+        let byte2 = (self.sport & 0x3F) | (self.dport & 0x03) << 6;
+
+        [byte0, byte1, byte2, self.flags]
+    }
 }
 
 impl Default for CspId {
@@ -171,6 +324,7 @@ impl CspConnection {
             idout: CspId::new(),
             opts: 0,
             state: ConnState::ConnClosed,
+            queue: None,
         }
     }
 }
@@ -189,6 +343,39 @@ mod tests {
     fn csppacket_test() {
         let test = CspPacket::new();
         assert_eq!(test.data, vec![0u8; 0]);
+
+        let mut p = CspPacket::new().data(vec![1, 2, 3]);
+        p.csp_crc32_append();
+        assert_eq!(p.data.len(), 7);
+        let mut corrupt = p.clone();
+        corrupt.data[0] ^= 1;
+        assert!(matches!(
+            corrupt.csp_crc32_verify(),
+            Err(CspError::CspErrCrc32)
+        ));
+        p.csp_crc32_verify().unwrap();
+        assert_eq!(p.data, vec![1, 2, 3]);
+        assert!(CspPacket::new().data(vec![1]).csp_crc32_verify().is_err());
+    }
+
+    #[test]
+    fn csperror_test() {
+        let e = CspError::from(io::Error::new(io::ErrorKind::TimedOut, "read"));
+        assert!(matches!(e, CspError::CspErrTimedOut));
+        assert_eq!(e.code(), -3);
+
+        let e = CspError::from(io::Error::new(io::ErrorKind::NotFound, "/dev/ttyUSB0"));
+        assert!(matches!(e, CspError::CspErrDriver(_)));
+        assert!(std::error::Error::source(&e).is_some());
+        assert!(e.to_string().contains("/dev/ttyUSB0"));
+
+        assert_eq!(
+            CspError::inval("bad port").to_string(),
+            "invalid argument: bad port"
+        );
+        assert_eq!(CspError::CspErrHmac.code(), -100);
+        assert_eq!(CspError::CspErrNoRoute.code(), -10);
+        assert_eq!(CspError::CspErrConnClosed.code(), -8);
     }
 
     #[test]
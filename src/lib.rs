@@ -9,13 +9,14 @@ extern crate log;
 //use std::sync::mpsc;
 //use std::sync::mpsc::{Sender, Receiver};
 
-pub fn csp_init() {
+pub fn csp_init(config: &config::CspConfig) -> Result<(), types::CspError> {
     //pretty_env_logger::init();
     info!("CSP library init...");
+    config.validate()?;
 
-    buffer::csp_buffer_init();
+    buffer::csp_buffer_init(config.buffers, config.buffer_data_size);
 
-    conn::csp_conn_init();
+    conn::csp_conn_init(config.conn_max);
 
     port::csp_port_init();
 
@@ -25,6 +26,7 @@ pub fn csp_init() {
 
     //let (tx, rx) : (Sender<CspPacket>, Receiver<CspPacket>) = mpsc::channel();
     info!("CSP library init... Done");
+    Ok(())
 }
 
 #[cfg(test)]
@@ -33,6 +35,7 @@ mod tests {
 
     #[test]
     fn it_works() {
-        csp_init();
+        csp_init(&config::CspConfig::default()).unwrap();
+        assert!(csp_init(&config::CspConfig::new().buffers(0, 256)).is_err());
     }
 }